@@ -0,0 +1,1045 @@
+//! Mock homeserver
+//!
+//! Scripted users and rooms plus the client-server endpoints used by mtxcli.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use ureq::serde_json::{json, Map, Value};
+
+use crate::http::{Request, Response};
+
+mod issuer;  use issuer::{DeviceGrant, ISSUER_PREFIX};
+mod uia;     use uia::UiaSession;
+
+/// Longest we will hold a /sync long poll
+const MAX_SYNC_TIMEOUT: u64 = 30000; // ms
+/// How often a /sync long poll checks for new events
+const SYNC_POLL: u64 = 50; // ms
+
+const CLIENT_PREFIX: &str = "/_matrix/client/";
+
+/// A scripted user
+#[derive(Debug, Deserialize)]
+pub struct ScriptUser {
+    /// localpart of the user id
+    pub user: String,
+    pub password: String,
+}
+
+/// A scripted message
+#[derive(Debug, Deserialize)]
+pub struct ScriptMessage {
+    /// full user id of the sender
+    pub sender: String,
+    pub body: String,
+}
+
+/// A scripted room
+#[derive(Debug, Deserialize)]
+pub struct ScriptRoom {
+    pub room_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// full user ids of the members
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub messages: Vec<ScriptMessage>,
+}
+
+/// How new users may register
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScriptRegistration {
+    /// the stages of user-interactive authentication of each flow
+    pub flows: Vec<Vec<String>>,
+    /// the token for the m.login.registration_token stage
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// The script for the mock homeserver
+#[derive(Debug, Deserialize)]
+pub struct Script {
+    pub server_name: String,
+    #[serde(default)]
+    pub users: Vec<ScriptUser>,
+    #[serde(default)]
+    pub rooms: Vec<ScriptRoom>,
+    /// localpart of the user who logs in with SSO or OIDC (offered if set)
+    #[serde(default)]
+    pub sso_user: Option<String>,
+    /// access tokens of clients using refresh tokens expire after this
+    #[serde(default)]
+    pub token_lifetime_ms: Option<u64>,
+    /// registration is disabled unless set
+    #[serde(default)]
+    pub registration: Option<ScriptRegistration>,
+}
+
+impl Script {
+    /// Users alice and bob (password "secret") in the rooms #test,
+    /// #random and an encrypted room without a name or alias,
+    /// alice may also log in with SSO or OIDC and new users may
+    /// register with the token "letmein" (accepting the terms)
+    pub fn new(server_name: &str) -> Self {
+        let alice = format!("@alice:{}", server_name);
+        let bob = format!("@bob:{}", server_name);
+        let room = ScriptRoom {
+            room_id: format!("!test:{}", server_name),
+            name: None,
+            aliases: vec![format!("#test:{}", server_name)],
+            encrypted: false,
+            members: vec![alice.clone(), bob.clone()],
+            messages: vec![ScriptMessage {
+                sender: bob.clone(),
+                body: "Welcome to the mock homeserver!".to_string(),
+            }],
+        };
+        let random = ScriptRoom {
+            room_id: format!("!random:{}", server_name),
+            name: Some("Random".to_string()),
+            aliases: vec![format!("#random:{}", server_name)],
+            encrypted: false,
+            members: vec![alice.clone(), bob.clone()],
+            messages: vec![ScriptMessage {
+                sender: bob.clone(),
+                body: "Anything goes here".to_string(),
+            }],
+        };
+        let direct = ScriptRoom {
+            room_id: format!("!direct:{}", server_name),
+            name: None,
+            aliases: Vec::new(),
+            encrypted: true,
+            members: vec![alice, bob.clone()],
+            messages: vec![ScriptMessage {
+                sender: bob,
+                body: "psst, alice".to_string(),
+            }],
+        };
+        Script {
+            server_name: server_name.to_string(),
+            users: vec![
+                ScriptUser { user: "alice".to_string(), password: "secret".to_string() },
+                ScriptUser { user: "bob".to_string(), password: "secret".to_string() },
+            ],
+            rooms: vec![room, random, direct],
+            sso_user: Some("alice".to_string()),
+            token_lifetime_ms: None,
+            registration: Some(ScriptRegistration {
+                flows: vec![vec![
+                    "m.login.registration_token".to_string(),
+                    "m.login.terms".to_string(),
+                ]],
+                token: Some("letmein".to_string()),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    token: String,
+    user_id: String,
+    device_id: String,
+    display_name: Option<String>,
+    refresh_token: Option<String>,
+    /// when the access token expires (only if there is a refresh token)
+    expires: Option<Instant>,
+    /// when the device logged in (ms since the epoch)
+    last_seen_ts: u64,
+}
+
+#[derive(Debug)]
+struct Room {
+    room_id: String,
+    name: Option<String>,
+    aliases: Vec<String>,
+    encrypted: bool,
+    members: Vec<String>,
+    /// invited by the creator (and not joined yet)
+    invited: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Event {
+    room_id: String,
+    event: Value,
+}
+
+/// The state of the mock homeserver
+#[derive(Debug)]
+pub struct Homeserver {
+    server_name: String,
+    users: Vec<ScriptUser>,
+    rooms: Vec<Room>,
+    /// all events, the position is the sync token
+    events: Vec<Event>,
+    sessions: Vec<Session>,
+    /// (token, txn_id, event_id) of messages sent
+    txns: Vec<(String, String, String)>,
+    sso_user: Option<String>,
+    /// (login_token, user_id) issued by SSO and not yet used
+    login_tokens: Vec<(String, String)>,
+    /// (client_id, client_name) registered with the issuer
+    clients: Vec<(String, Option<String>)>,
+    device_grants: Vec<DeviceGrant>,
+    token_lifetime: Option<Duration>,
+    registration: Option<ScriptRegistration>,
+    uia_sessions: Vec<UiaSession>,
+    next_id: u64,
+}
+
+/// Milliseconds since the epoch
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl Homeserver {
+    pub fn new(script: Script) -> Self {
+        let mut homeserver = Homeserver {
+            server_name: script.server_name,
+            users: script.users,
+            rooms: Vec::new(),
+            events: Vec::new(),
+            sessions: Vec::new(),
+            txns: Vec::new(),
+            sso_user: script.sso_user,
+            login_tokens: Vec::new(),
+            clients: Vec::new(),
+            device_grants: Vec::new(),
+            token_lifetime: script.token_lifetime_ms.map(Duration::from_millis),
+            registration: script.registration,
+            uia_sessions: Vec::new(),
+            next_id: 1,
+        };
+        for room in script.rooms {
+            for message in room.messages.iter() {
+                homeserver.add_message(&room.room_id, &message.sender, &message.body);
+            }
+            homeserver.rooms.push(Room {
+                room_id: room.room_id,
+                name: room.name,
+                aliases: room.aliases,
+                encrypted: room.encrypted,
+                members: room.members,
+                invited: Vec::new(),
+            });
+        }
+        homeserver
+    }
+
+    fn gen_id(&mut self, prefix: &str) -> String {
+        let id = format!("{}{}", prefix, self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn user_id(&self, user: &str) -> String {
+        if user.starts_with('@') {
+            user.to_string()
+        } else {
+            format!("@{}:{}", user, self.server_name)
+        }
+    }
+
+    fn add_message(&mut self, room_id: &str, sender: &str, body: &str) -> String {
+        let event_id = format!("${}", self.gen_id("event"));
+        self.events.push(Event {
+            room_id: room_id.to_string(),
+            event: json!({
+                "event_id": event_id,
+                "type": "m.room.message",
+                "sender": sender,
+                "content": { "msgtype": "m.text", "body": body },
+            }),
+        });
+        event_id
+    }
+
+    /// Record that the user joined or left the room
+    fn add_membership(&mut self, room_id: &str, user_id: &str, membership: &str) {
+        let event_id = format!("${}", self.gen_id("event"));
+        self.events.push(Event {
+            room_id: room_id.to_string(),
+            event: json!({
+                "event_id": event_id,
+                "type": "m.room.member",
+                "sender": user_id,
+                "state_key": user_id,
+                "content": { "membership": membership },
+            }),
+        });
+    }
+
+    /// Returns the session for the request or the error response
+    fn session(&self, request: &Request) -> Result<&Session, Response> {
+        match request.token() {
+            Some(token) => {
+                match self.sessions.iter().find(|session| session.token == token) {
+                    Some(session) if matches!(session.expires, Some(expires) if Instant::now() >= expires) => {
+                        // the client should use its refresh token
+                        Err(Response::json(401, json!({
+                            "errcode": "M_UNKNOWN_TOKEN",
+                            "error": "Access token has expired",
+                            "soft_logout": true,
+                        })))
+                    },
+                    Some(session) => Ok(session),
+                    None => Err(Response::error(401, "M_UNKNOWN_TOKEN", "Unknown access token")),
+                }
+            },
+            None => {
+                Err(Response::error(401, "M_MISSING_TOKEN", "Missing access token"))
+            }
+        }
+    }
+
+    fn room(&self, room_id: &str) -> Option<&Room> {
+        self.rooms.iter().find(|room| room.room_id == room_id)
+    }
+
+    // --------------------------------
+
+    fn versions(&self) -> Response {
+        Response::json(200, json!({
+            "versions": ["r0.6.1", "v1.1", "v1.2", "v1.3"],
+        }))
+    }
+
+    fn login_flows(&self) -> Response {
+        let mut flows = vec![ json!({ "type": "m.login.password" }) ];
+        if self.sso_user.is_some() {
+            flows.push(json!({ "type": "m.login.sso" }));
+            flows.push(json!({ "type": "m.login.token" }));
+        }
+        Response::json(200, json!({ "flows": flows }))
+    }
+
+    /// Login as the device_id from the body (if any) else a new device
+    fn new_session(&mut self, user_id: String, body: &Value) -> Response {
+        let token = self.gen_id("token");
+        let device_id = match body.get("device_id").and_then(Value::as_str) {
+            Some(device_id) => {
+                // logging in as an existing device logs out the old session
+                self.sessions.retain(|session| session.user_id != user_id || session.device_id != device_id);
+                device_id.to_string()
+            },
+            None => self.gen_id("DEVICE"),
+        };
+        let display_name = body.get("initial_device_display_name")
+            .and_then(Value::as_str)
+            .map(|display_name| display_name.to_string());
+        self.sessions.push(Session {
+            token: token.clone(),
+            user_id: user_id.clone(),
+            device_id: device_id.clone(),
+            display_name,
+            refresh_token: None,
+            expires: None,
+            last_seen_ts: now_ms(),
+        });
+        Response::json(200, json!({
+            "user_id": user_id,
+            "access_token": token,
+            "device_id": device_id,
+        }))
+    }
+
+    /// Issue new access and refresh tokens for session i (refresh
+    /// tokens are single use), returns the response fields
+    fn renew_tokens(&mut self, i: usize) -> Map<String, Value> {
+        let token = self.gen_id("token");
+        let refresh_token = self.gen_id("refresh");
+        let session = &mut self.sessions[i];
+        session.token = token.clone();
+        session.refresh_token = Some(refresh_token.clone());
+        session.expires = self.token_lifetime.map(|lifetime| Instant::now() + lifetime);
+        let mut fields = Map::new();
+        fields.insert("access_token".to_string(), json!(token));
+        fields.insert("refresh_token".to_string(), json!(refresh_token));
+        if let Some(lifetime) = self.token_lifetime {
+            fields.insert("expires_in_ms".to_string(), json!(lifetime.as_millis() as u64));
+        }
+        fields
+    }
+
+    fn login(&mut self, request: &Request) -> Response {
+        let body = request.json();
+        let response = match body.get("type").and_then(Value::as_str) {
+            Some("m.login.password") => self.login_password(&body),
+            Some("m.login.token") => self.login_token(&body),
+            _ => Response::error(400, "M_UNKNOWN", "Unsupported login type"),
+        };
+        self.with_refresh_token(&body, response)
+    }
+
+    /// Add a refresh token to a successful login (if the client asked for one)
+    fn with_refresh_token(&mut self, body: &Value, response: Response) -> Response {
+        if response.status == 200 && body.get("refresh_token") == Some(&Value::Bool(true)) {
+            let i = self.sessions.len() - 1;
+            let mut fields = self.renew_tokens(i);
+            let session = &self.sessions[i];
+            fields.insert("user_id".to_string(), json!(session.user_id));
+            fields.insert("device_id".to_string(), json!(session.device_id));
+            Response::json(200, Value::Object(fields))
+        } else {
+            response
+        }
+    }
+
+    fn register(&mut self, request: &Request) -> Response {
+        let registration = match &self.registration {
+            Some(registration) => registration.clone(),
+            None => {
+                return Response::error(403, "M_FORBIDDEN", "Registration has been disabled");
+            }
+        };
+        let body = request.json();
+        let username = body.get("username").and_then(Value::as_str).unwrap_or_default().to_string();
+        let password = body.get("password").and_then(Value::as_str).unwrap_or_default().to_string();
+        if username.is_empty() || password.is_empty() {
+            return Response::error(400, "M_MISSING_PARAM", "Missing username or password");
+        }
+        if self.users.iter().any(|user| user.user == username) {
+            return Response::error(400, "M_USER_IN_USE", "User ID already taken.");
+        }
+        let params = json!({
+            "m.login.terms": {
+                "policies": {
+                    "privacy_policy": {
+                        "version": "1.0",
+                        "en": {
+                            "name": "Privacy Policy",
+                            "url": "https://example.org/privacy-1.0.html",
+                        },
+                    },
+                },
+            },
+        });
+        if let Err(response) = self.interactive_auth(&body, &registration.flows, params, None) {
+            return response;
+        }
+        self.users.push(ScriptUser { user: username.clone(), password });
+        let user_id = self.user_id(&username);
+        let response = self.new_session(user_id, &body);
+        self.with_refresh_token(&body, response)
+    }
+
+    /// The flows for account management: confirm with the password
+    fn password_flows() -> Vec<Vec<String>> {
+        vec![vec!["m.login.password".to_string()]]
+    }
+
+    fn change_password(&mut self, request: &Request) -> Response {
+        let (user_id, device_id) = match self.session(request) {
+            Ok(session) => (session.user_id.clone(), session.device_id.clone()),
+            Err(response) => {
+                return response;
+            }
+        };
+        let body = request.json();
+        let new_password = body.get("new_password").and_then(Value::as_str).unwrap_or_default().to_string();
+        if new_password.is_empty() {
+            return Response::error(400, "M_MISSING_PARAM", "Missing new_password");
+        }
+        if let Err(response) = self.interactive_auth(&body, &Self::password_flows(), json!({}), Some(&user_id)) {
+            return response;
+        }
+        if let Some(i) = self.users.iter().position(|user| self.user_id(&user.user) == user_id) {
+            self.users[i].password = new_password;
+        }
+        // logout_devices defaults to true: all but this device
+        if body.get("logout_devices") != Some(&Value::Bool(false)) {
+            self.sessions.retain(|session| session.user_id != user_id || session.device_id == device_id);
+        }
+        Response::json(200, json!({}))
+    }
+
+    fn deactivate(&mut self, request: &Request) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let body = request.json();
+        if let Err(response) = self.interactive_auth(&body, &Self::password_flows(), json!({}), Some(&user_id)) {
+            return response;
+        }
+        if let Some(i) = self.users.iter().position(|user| self.user_id(&user.user) == user_id) {
+            self.users.remove(i);
+        }
+        self.sessions.retain(|session| session.user_id != user_id);
+        for room in self.rooms.iter_mut() {
+            room.members.retain(|member| *member != user_id);
+        }
+        Response::json(200, json!({ "id_server_unbind_result": "no-support" }))
+    }
+
+    fn refresh(&mut self, request: &Request) -> Response {
+        let body = request.json();
+        let refresh_token = body.get("refresh_token").and_then(Value::as_str).unwrap_or_default();
+        match self.sessions.iter().position(|session| session.refresh_token.as_deref() == Some(refresh_token)) {
+            Some(i) => Response::json(200, Value::Object(self.renew_tokens(i))),
+            None => Response::error(401, "M_UNKNOWN_TOKEN", "Unknown refresh token"),
+        }
+    }
+
+    fn login_password(&mut self, body: &Value) -> Response {
+        let user = body.get("identifier")
+            .and_then(|identifier| identifier.get("user"))
+            .or_else(|| body.get("user"))
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let password = body.get("password").and_then(Value::as_str).unwrap_or_default();
+        let user_id = self.user_id(user);
+        let valid = self.users.iter()
+            .any(|u| self.user_id(&u.user) == user_id && u.password == password);
+        if ! valid {
+            return Response::error(403, "M_FORBIDDEN", "Invalid username or password");
+        }
+        self.new_session(user_id, body)
+    }
+
+    fn login_token(&mut self, body: &Value) -> Response {
+        let login_token = body.get("token").and_then(Value::as_str).unwrap_or_default();
+        match self.login_tokens.iter().position(|(token, _)| token == login_token) {
+            Some(i) => {
+                let (_, user_id) = self.login_tokens.remove(i);
+                self.new_session(user_id, body)
+            },
+            None => {
+                Response::error(403, "M_FORBIDDEN", "Invalid login token")
+            }
+        }
+    }
+
+    /// Pretend the user logged in with the identity provider
+    fn sso_redirect(&mut self, request: &Request) -> Response {
+        let sso_user = match &self.sso_user {
+            Some(sso_user) => sso_user.clone(),
+            None => {
+                return Response::error(404, "M_UNRECOGNIZED", "SSO is not supported");
+            }
+        };
+        let redirect_url = match request.param("redirectUrl") {
+            Some(redirect_url) => redirect_url.to_string(),
+            None => {
+                return Response::error(400, "M_MISSING_PARAM", "Missing redirectUrl");
+            }
+        };
+        let login_token = self.gen_id("login");
+        let user_id = self.user_id(&sso_user);
+        self.login_tokens.push((login_token.clone(), user_id));
+        let separator = if redirect_url.contains('?') { '&' } else { '?' };
+        Response::redirect(&format!("{}{}loginToken={}", redirect_url, separator, login_token))
+    }
+
+    fn whoami(&self, request: &Request) -> Response {
+        match self.session(request) {
+            Ok(session) => {
+                Response::json(200, json!({
+                    "user_id": session.user_id,
+                    "device_id": session.device_id,
+                }))
+            },
+            Err(response) => response
+        }
+    }
+
+    fn logout(&mut self, request: &Request, all: bool) -> Response {
+        let (token, user_id) = match self.session(request) {
+            Ok(session) => (session.token.clone(), session.user_id.clone()),
+            Err(response) => {
+                return response;
+            }
+        };
+        if all {
+            self.sessions.retain(|session| session.user_id != user_id);
+        } else {
+            self.sessions.retain(|session| session.token != token);
+        }
+        Response::json(200, json!({}))
+    }
+
+    fn devices(&self, request: &Request) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let devices: Vec<Value> = self.sessions.iter()
+            .filter(|session| session.user_id == user_id)
+            .map(|session| json!({
+                "device_id": session.device_id,
+                "display_name": session.display_name,
+                "last_seen_ip": "127.0.0.1",
+                "last_seen_ts": session.last_seen_ts,
+            }))
+            .collect();
+        Response::json(200, json!({ "devices": devices }))
+    }
+
+    fn rename_device(&mut self, request: &Request, device_id: &str) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let display_name = request.json().get("display_name")
+            .and_then(Value::as_str)
+            .map(|display_name| display_name.to_string());
+        match self.sessions.iter_mut().find(|session| session.user_id == user_id && session.device_id == device_id) {
+            Some(session) => {
+                session.display_name = display_name;
+                Response::json(200, json!({}))
+            },
+            None => Response::error(404, "M_NOT_FOUND", "Unknown device"),
+        }
+    }
+
+    /// Delete the devices (logging them out) once the user is authenticated
+    fn delete_devices(&mut self, request: &Request) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let body = request.json();
+        let devices: Vec<String> = match body.get("devices").and_then(Value::as_array) {
+            Some(devices) => devices.iter().filter_map(Value::as_str).map(|d| d.to_string()).collect(),
+            None => {
+                return Response::error(400, "M_MISSING_PARAM", "Missing devices");
+            }
+        };
+        if let Err(response) = self.interactive_auth(&body, &Self::password_flows(), json!({}), Some(&user_id)) {
+            return response;
+        }
+        self.sessions.retain(|session| session.user_id != user_id || ! devices.contains(&session.device_id));
+        Response::json(200, json!({}))
+    }
+
+    fn directory(&self, request: &Request, alias: &str) -> Response {
+        if let Err(response) = self.session(request) {
+            return response;
+        }
+        match self.rooms.iter().find(|room| room.aliases.iter().any(|a| a == alias)) {
+            Some(room) => {
+                Response::json(200, json!({
+                    "room_id": room.room_id,
+                    "servers": [ self.server_name ],
+                }))
+            },
+            None => {
+                Response::error(404, "M_NOT_FOUND", &format!("Room alias {} not found", alias))
+            }
+        }
+    }
+
+    fn joined_rooms(&self, request: &Request) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let room_ids: Vec<&String> = self.rooms.iter()
+            .filter(|room| room.members.contains(&user_id))
+            .map(|room| &room.room_id)
+            .collect();
+        Response::json(200, json!({ "joined_rooms": room_ids }))
+    }
+
+    // the room (if the user is a member) or the error response
+    fn member_room(&self, request: &Request, room_id: &str) -> Result<&Room, Response> {
+        let user_id = self.session(request)?.user_id.clone();
+        match self.room(room_id) {
+            Some(room) if room.members.contains(&user_id) => Ok(room),
+            Some(_) => Err(Response::error(403, "M_FORBIDDEN", "User is not in the room")),
+            None => Err(Response::error(404, "M_NOT_FOUND", "Unknown room")),
+        }
+    }
+
+    /// The content of a state event (of those in room_state)
+    fn state_event(&self, request: &Request, room_id: &str, event_type: &str) -> Response {
+        let room = match self.member_room(request, room_id) {
+            Ok(room) => room,
+            Err(response) => {
+                return response;
+            }
+        };
+        match Self::room_state(room).into_iter().find(|event| event["type"] == event_type) {
+            Some(event) => Response::json(200, event["content"].clone()),
+            None => Response::error(404, "M_NOT_FOUND", "Event not found"),
+        }
+    }
+
+    fn joined_members(&self, request: &Request, room_id: &str) -> Response {
+        let room = match self.member_room(request, room_id) {
+            Ok(room) => room,
+            Err(response) => {
+                return response;
+            }
+        };
+        let joined: Map<String, Value> = room.members.iter()
+            .map(|member| (member.to_string(), json!({})))
+            .collect();
+        Response::json(200, json!({ "joined": joined }))
+    }
+
+    fn filter(&mut self, request: &Request, user_id: &str) -> Response {
+        match self.session(request) {
+            Ok(session) if session.user_id == user_id => { },
+            Ok(_) => {
+                return Response::error(403, "M_FORBIDDEN", "Cannot create filters for other users");
+            },
+            Err(response) => {
+                return response;
+            }
+        }
+        let filter_id = self.gen_id("");
+        Response::json(200, json!({ "filter_id": filter_id }))
+    }
+
+    /// The state events of the room (its name and canonical alias)
+    fn room_state(room: &Room) -> Vec<Value> {
+        let mut state = Vec::new();
+        if let Some(name) = &room.name {
+            state.push(json!({
+                "type": "m.room.name",
+                "state_key": "",
+                "content": { "name": name },
+            }));
+        }
+        if let Some(alias) = room.aliases.first() {
+            state.push(json!({
+                "type": "m.room.canonical_alias",
+                "state_key": "",
+                "content": { "alias": alias },
+            }));
+        }
+        if room.encrypted {
+            state.push(json!({
+                "type": "m.room.encryption",
+                "state_key": "",
+                "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+            }));
+        }
+        state
+    }
+
+    /// The room summary for the user (the heroes are the other members)
+    fn room_summary(room: &Room, user_id: &str) -> Value {
+        let heroes: Vec<&String> = room.members.iter()
+            .chain(room.invited.iter())
+            .filter(|member| *member != user_id)
+            .take(5)
+            .collect();
+        json!({
+            "m.heroes": heroes,
+            "m.joined_member_count": room.members.len(),
+            "m.invited_member_count": room.invited.len(),
+        })
+    }
+
+    /// Messages of others since the user last spoke in the room are
+    /// unread (highlighted if they mention the user)
+    fn unread_notifications(&self, room: &Room, user_id: &str) -> Value {
+        let localpart = user_id.trim_start_matches('@').split(':').next().unwrap_or_default();
+        let unread: Vec<&Value> = self.events.iter()
+            .filter(|event| event.room_id == room.room_id && event.event["type"] == "m.room.message")
+            .map(|event| &event.event)
+            .collect();
+        let unread = match unread.iter().rposition(|event| event["sender"] == user_id) {
+            Some(i) => &unread[i + 1..],
+            None => &unread[..],
+        };
+        let highlights = unread.iter()
+            .filter(|event| event["content"]["body"].as_str().unwrap_or_default().contains(localpart))
+            .count();
+        json!({
+            "notification_count": unread.len(),
+            "highlight_count": highlights,
+        })
+    }
+
+    /// Returns the sync response if there is anything new since (an
+    /// initial sync has all the joined rooms with their state, as do
+    /// rooms joined since)
+    fn sync_since(&self, user_id: &str, since: usize) -> Option<Value> {
+        let mut join = Map::new();
+        let mut leave = Map::new();
+        for room in self.rooms.iter() {
+            let events: Vec<Value> = self.events.iter()
+                .skip(since)
+                .filter(|event| event.room_id == room.room_id)
+                .map(|event| event.event.clone())
+                .collect();
+            // the last change of membership of the user since
+            let membership = events.iter().rev()
+                .find(|event| event["type"] == "m.room.member" && event["state_key"] == user_id)
+                .cloned();
+            let membership_is = |value: &str| {
+                matches!(&membership, Some(event) if event["content"]["membership"] == value)
+            };
+            if ! room.members.iter().any(|member| member == user_id) {
+                if since > 0 && membership_is("leave") {
+                    leave.insert(room.room_id.clone(), json!({
+                        "timeline": { "events": [ membership ], "limited": false },
+                    }));
+                }
+                continue;
+            }
+            if since == 0 || membership_is("join") {
+                join.insert(room.room_id.clone(), json!({
+                    "state": { "events": Self::room_state(room) },
+                    "timeline": { "events": events, "limited": false },
+                    "summary": Self::room_summary(room, user_id),
+                    "unread_notifications": self.unread_notifications(room, user_id),
+                }));
+            } else if events.len() > 0 {
+                join.insert(room.room_id.clone(), json!({
+                    "timeline": { "events": events, "limited": false },
+                    "unread_notifications": self.unread_notifications(room, user_id),
+                }));
+            }
+        }
+        if join.len() > 0 || leave.len() > 0 || since == 0 {
+            Some(json!({
+                "next_batch": self.events.len().to_string(),
+                "rooms": { "join": join, "leave": leave },
+            }))
+        } else {
+            None
+        }
+    }
+
+    fn send(&mut self, request: &Request, room_id: &str, event_type: &str, txn_id: &str) -> Response {
+        let (token, user_id) = match self.session(request) {
+            Ok(session) => (session.token.clone(), session.user_id.clone()),
+            Err(response) => {
+                return response;
+            }
+        };
+        match self.room(room_id) {
+            Some(room) if room.members.contains(&user_id) => { },
+            Some(_) => {
+                return Response::error(403, "M_FORBIDDEN", "User is not in the room");
+            },
+            None => {
+                return Response::error(404, "M_NOT_FOUND", "Unknown room");
+            }
+        }
+        if let Some((_, _, event_id)) = self.txns.iter().find(|(t, txn, _)| *t == token && txn == txn_id) {
+            return Response::json(200, json!({ "event_id": event_id }));
+        }
+        if event_type != "m.room.message" {
+            return Response::error(400, "M_UNKNOWN", "Only m.room.message is supported");
+        }
+        let body = request.json();
+        let text = body.get("body").and_then(Value::as_str).unwrap_or_default();
+        let event_id = self.add_message(room_id, &user_id, text);
+        self.txns.push((token, txn_id.to_string(), event_id.clone()));
+        Response::json(200, json!({ "event_id": event_id }))
+    }
+
+    fn join(&mut self, request: &Request, room_id_or_alias: &str) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let i = match self.rooms.iter().position(|room| {
+            room.room_id == room_id_or_alias || room.aliases.iter().any(|alias| alias == room_id_or_alias)
+        }) {
+            Some(i) => i,
+            None => {
+                return Response::error(404, "M_NOT_FOUND", &format!("Room {} not found", room_id_or_alias));
+            }
+        };
+        let room_id = self.rooms[i].room_id.clone();
+        if ! self.rooms[i].members.contains(&user_id) {
+            self.rooms[i].invited.retain(|invited| *invited != user_id);
+            self.rooms[i].members.push(user_id.clone());
+            self.add_membership(&room_id, &user_id, "join");
+        }
+        Response::json(200, json!({ "room_id": room_id }))
+    }
+
+    /// Create a room with the user as its only member (the topic and
+    /// visibility are accepted but not kept)
+    fn create_room(&mut self, request: &Request) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        let body = request.json();
+        let mut aliases = Vec::new();
+        if let Some(alias_name) = body.get("room_alias_name").and_then(Value::as_str) {
+            let alias = format!("#{}:{}", alias_name, self.server_name);
+            if self.rooms.iter().any(|room| room.aliases.contains(&alias)) {
+                return Response::error(400, "M_ROOM_IN_USE", &format!("Room alias {} already taken", alias));
+            }
+            aliases.push(alias);
+        }
+        let encrypted = body.get("initial_state").and_then(Value::as_array).into_iter().flatten()
+            .any(|event| event["type"] == "m.room.encryption");
+        let invited: Vec<String> = body.get("invite").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(Value::as_str)
+            .map(|invited| invited.to_string())
+            .collect();
+        let room_id = format!("!{}:{}", self.gen_id("room"), self.server_name);
+        self.rooms.push(Room {
+            room_id: room_id.clone(),
+            name: body.get("name").and_then(Value::as_str).map(|name| name.to_string()),
+            aliases,
+            encrypted,
+            members: vec![ user_id.clone() ],
+            invited,
+        });
+        self.add_membership(&room_id, &user_id, "join");
+        Response::json(200, json!({ "room_id": room_id }))
+    }
+
+    fn leave(&mut self, request: &Request, room_id: &str) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        match self.rooms.iter_mut().find(|room| room.room_id == room_id) {
+            Some(room) if room.members.contains(&user_id) => {
+                room.members.retain(|member| *member != user_id);
+            },
+            Some(_) => {
+                return Response::error(403, "M_FORBIDDEN", "User is not in the room");
+            },
+            None => {
+                return Response::error(404, "M_NOT_FOUND", "Unknown room");
+            }
+        }
+        self.add_membership(room_id, &user_id, "leave");
+        Response::json(200, json!({}))
+    }
+
+    /// Only rooms the user has left may be forgotten
+    fn forget(&mut self, request: &Request, room_id: &str) -> Response {
+        let user_id = match self.session(request) {
+            Ok(session) => session.user_id.clone(),
+            Err(response) => {
+                return response;
+            }
+        };
+        match self.room(room_id) {
+            Some(room) if room.members.contains(&user_id) => {
+                Response::error(400, "M_UNKNOWN", "User is in the room")
+            },
+            Some(_) => Response::json(200, json!({})),
+            None => Response::error(404, "M_NOT_FOUND", "Unknown room"),
+        }
+    }
+
+    /// Route requests which need exclusive access to the homeserver
+    fn route(&mut self, request: &Request, endpoint: &[&str]) -> Response {
+        match (request.method.as_str(), endpoint) {
+            ("GET", ["login"]) => self.login_flows(),
+            ("POST", ["login"]) => self.login(request),
+            ("POST", ["register"]) => self.register(request),
+            ("POST", ["refresh"]) => self.refresh(request),
+            ("POST", ["account", "password"]) => self.change_password(request),
+            ("POST", ["account", "deactivate"]) => self.deactivate(request),
+            ("POST", ["logout"]) => self.logout(request, false),
+            ("POST", ["logout", "all"]) => self.logout(request, true),
+            ("GET", ["login", "sso", "redirect"]) => self.sso_redirect(request),
+            ("GET", ["account", "whoami"]) => self.whoami(request),
+            ("GET", ["devices"]) => self.devices(request),
+            ("PUT", ["devices", device_id]) => self.rename_device(request, device_id),
+            ("POST", ["delete_devices"]) => self.delete_devices(request),
+            ("GET", ["auth_metadata"]) => self.auth_metadata(request),
+            ("GET", ["org.matrix.msc2965", "auth_issuer"]) => self.auth_issuer(request),
+            ("GET", ["directory", "room", alias]) => self.directory(request, alias),
+            ("POST", ["user", user_id, "filter"]) => self.filter(request, user_id),
+            ("GET", ["joined_rooms"]) => self.joined_rooms(request),
+            ("GET", ["rooms", room_id, "state", event_type, ""]) => self.state_event(request, room_id, event_type),
+            ("GET", ["rooms", room_id, "joined_members"]) => self.joined_members(request, room_id),
+            ("POST", ["createRoom"]) => self.create_room(request),
+            ("POST", ["join", room_id_or_alias]) => self.join(request, room_id_or_alias),
+            ("POST", ["rooms", room_id, "leave"]) => self.leave(request, room_id),
+            ("POST", ["rooms", room_id, "forget"]) => self.forget(request, room_id),
+            ("PUT", ["rooms", room_id, "send", event_type, txn_id]) => {
+                self.send(request, room_id, event_type, txn_id)
+            },
+            _ => {
+                Response::error(404, "M_UNRECOGNIZED", "Unrecognized request")
+            }
+        }
+    }
+}
+
+/// Long poll /sync (without holding the lock while waiting)
+fn sync(homeserver: &Mutex<Homeserver>, request: &Request) -> Response {
+    let since = request.param("since")
+        .and_then(|since| since.parse::<usize>().ok())
+        .unwrap_or(0);
+    let timeout = request.param("timeout")
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .unwrap_or(0)
+        .min(MAX_SYNC_TIMEOUT);
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    loop {
+        {
+            let homeserver = homeserver.lock().unwrap();
+            let user_id = match homeserver.session(request) {
+                Ok(session) => session.user_id.clone(),
+                Err(response) => {
+                    return response;
+                }
+            };
+            if let Some(body) = homeserver.sync_since(&user_id, since) {
+                return Response::json(200, body);
+            }
+            if Instant::now() >= deadline {
+                return Response::json(200, json!({
+                    "next_batch": since.to_string(),
+                    "rooms": { "join": {} },
+                }));
+            }
+        }
+        thread::sleep(Duration::from_millis(SYNC_POLL));
+    }
+}
+
+/// Handle one request
+pub fn handle(homeserver: &Mutex<Homeserver>, request: &Request) -> Response {
+    if let Some(endpoint) = request.path.strip_prefix(ISSUER_PREFIX) {
+        return issuer::route(&mut homeserver.lock().unwrap(), request, endpoint);
+    }
+    let path = match request.path.strip_prefix(CLIENT_PREFIX) {
+        Some(path) => path,
+        None => {
+            return Response::error(404, "M_UNRECOGNIZED", "Unrecognized request");
+        }
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["versions"]) => homeserver.lock().unwrap().versions(),
+        ("GET", [_version, "sync"]) => sync(homeserver, request),
+        (_, [_version, endpoint @ ..]) => homeserver.lock().unwrap().route(request, endpoint),
+        _ => Response::error(404, "M_UNRECOGNIZED", "Unrecognized request"),
+    }
+}
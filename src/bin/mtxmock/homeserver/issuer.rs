@@ -0,0 +1,241 @@
+//! Stand-in OpenID Connect issuer
+//!
+//! Just enough of an issuer (MSC3861) for the device authorization grant:
+//! client registration, device authorization and the token endpoint.
+//! Opening the verification URI approves the login as the SSO user.
+
+use ureq::serde_json::{json, Value};
+
+use crate::http::{self, Request, Response};
+use super::{now_ms, Homeserver, Session};
+
+pub const ISSUER_PREFIX: &str = "/oauth2/";
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const REFRESH_TOKEN_GRANT: &str = "refresh_token";
+const SCOPE_DEVICE: &str = "urn:matrix:org.matrix.msc2967.client:device:";
+
+/// Seconds until a device code expires
+const DEVICE_EXPIRES_IN: u64 = 600;
+/// Seconds the client should wait between polls
+const DEVICE_INTERVAL: u64 = 1;
+
+/// A device authorization grant in progress
+#[derive(Debug)]
+pub struct DeviceGrant {
+    device_code: String,
+    user_code: String,
+    client_id: String,
+    /// the display name of the device
+    client_name: Option<String>,
+    device_id: String,
+    /// set once the user has approved the login
+    user_id: Option<String>,
+}
+
+/// The issuer URL (as seen by the client)
+fn issuer(request: &Request) -> String {
+    format!("http://{}{}", request.header("Host").unwrap_or("127.0.0.1"), ISSUER_PREFIX)
+}
+
+/// A standard OAuth 2.0 error response
+fn oauth_error(status: u16, error: &str, description: &str) -> Response {
+    Response::json(status, json!({
+        "error": error,
+        "error_description": description,
+    }))
+}
+
+/// Returns the form field named name
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields.iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+impl Homeserver {
+    fn metadata(&self, request: &Request) -> Value {
+        let issuer = issuer(request);
+        json!({
+            "issuer": issuer,
+            "registration_endpoint": format!("{}registration", issuer),
+            "device_authorization_endpoint": format!("{}device", issuer),
+            "token_endpoint": format!("{}token", issuer),
+            "revocation_endpoint": format!("{}revoke", issuer),
+            "grant_types_supported": [ DEVICE_CODE_GRANT, REFRESH_TOKEN_GRANT ],
+        })
+    }
+
+    /// GET /_matrix/client/v1/auth_metadata
+    pub(super) fn auth_metadata(&self, request: &Request) -> Response {
+        if self.sso_user.is_none() {
+            return Response::error(404, "M_UNRECOGNIZED", "OIDC is not supported");
+        }
+        Response::json(200, self.metadata(request))
+    }
+
+    /// GET /_matrix/client/unstable/org.matrix.msc2965/auth_issuer
+    pub(super) fn auth_issuer(&self, request: &Request) -> Response {
+        if self.sso_user.is_none() {
+            return Response::error(404, "M_UNRECOGNIZED", "OIDC is not supported");
+        }
+        Response::json(200, json!({ "issuer": issuer(request) }))
+    }
+
+    fn register_client(&mut self, request: &Request) -> Response {
+        let body = request.json();
+        let grant_types = body.get("grant_types").and_then(Value::as_array);
+        let device_code_grant = grant_types
+            .map(|grant_types| grant_types.iter().any(|grant_type| grant_type == DEVICE_CODE_GRANT))
+            .unwrap_or(false);
+        if ! device_code_grant {
+            return oauth_error(400, "invalid_client_metadata", "grant_types must include the device code grant");
+        }
+        let client_id = self.gen_id("client");
+        let client_name = body.get("client_name")
+            .and_then(Value::as_str)
+            .map(|client_name| client_name.to_string());
+        self.clients.push((client_id.clone(), client_name));
+        Response::json(201, json!({ "client_id": client_id }))
+    }
+
+    fn device_authorization(&mut self, request: &Request) -> Response {
+        let fields = http::parse_params(&request.body);
+        let (client_id, client_name) = match field(&fields, "client_id")
+            .and_then(|client_id| self.clients.iter().find(|(c, _)| c == client_id)) {
+            Some(client) => client.clone(),
+            None => {
+                return oauth_error(401, "invalid_client", "Unknown client_id");
+            }
+        };
+        let scope = field(&fields, "scope").unwrap_or_default();
+        let device_id = match scope.split(' ').find_map(|scope| scope.strip_prefix(SCOPE_DEVICE)) {
+            Some(device_id) => device_id.to_string(),
+            None => self.gen_id("DEVICE"),
+        };
+        let device_code = self.gen_id("device_code");
+        let user_code = format!("CODE-{}", self.gen_id(""));
+        let verification_uri = format!("{}device", issuer(request));
+        self.device_grants.push(DeviceGrant {
+            device_code: device_code.clone(),
+            user_code: user_code.clone(),
+            client_id,
+            client_name,
+            device_id,
+            user_id: None,
+        });
+        Response::json(200, json!({
+            "device_code": device_code,
+            "user_code": user_code,
+            "verification_uri": verification_uri,
+            "verification_uri_complete": format!("{}?user_code={}", verification_uri, user_code),
+            "expires_in": DEVICE_EXPIRES_IN,
+            "interval": DEVICE_INTERVAL,
+        }))
+    }
+
+    /// Pretend the user logged in and approved the device
+    fn verify_device(&mut self, request: &Request) -> Response {
+        let user_id = match &self.sso_user {
+            Some(sso_user) => self.user_id(sso_user),
+            None => {
+                return oauth_error(400, "access_denied", "No SSO user");
+            }
+        };
+        let user_code = request.param("user_code").unwrap_or_default();
+        match self.device_grants.iter_mut().find(|grant| grant.user_code == user_code) {
+            Some(grant) => {
+                grant.user_id = Some(user_id.clone());
+                Response::html(200, &format!("<html><body><p>Approved {} for {}</p></body></html>",
+                                             grant.device_id, user_id))
+            },
+            None => {
+                oauth_error(400, "invalid_request", "Unknown user_code")
+            }
+        }
+    }
+
+    fn token(&mut self, request: &Request) -> Response {
+        let fields = http::parse_params(&request.body);
+        match field(&fields, "grant_type") {
+            Some(DEVICE_CODE_GRANT) => self.device_code_token(&fields),
+            Some(REFRESH_TOKEN_GRANT) => self.refresh_token(&fields),
+            _ => oauth_error(400, "unsupported_grant_type", "Unsupported grant_type"),
+        }
+    }
+
+    fn device_code_token(&mut self, fields: &[(String, String)]) -> Response {
+        let device_code = field(fields, "device_code").unwrap_or_default();
+        let client_id = field(fields, "client_id").unwrap_or_default();
+        let i = match self.device_grants.iter()
+            .position(|grant| grant.device_code == device_code && grant.client_id == client_id) {
+            Some(i) => i,
+            None => {
+                return oauth_error(400, "invalid_grant", "Unknown device_code");
+            }
+        };
+        if self.device_grants[i].user_id.is_none() {
+            return oauth_error(400, "authorization_pending", "The user has not approved the login yet");
+        }
+        let grant = self.device_grants.remove(i);
+        let user_id = grant.user_id.unwrap_or_default();
+        // logging in as an existing device logs out the old session
+        self.sessions.retain(|session| session.user_id != user_id || session.device_id != grant.device_id);
+        self.sessions.push(Session {
+            token: String::new(),
+            user_id,
+            device_id: grant.device_id,
+            display_name: grant.client_name,
+            refresh_token: None,
+            expires: None,
+            last_seen_ts: now_ms(),
+        });
+        let i = self.sessions.len() - 1;
+        self.issue_tokens(i)
+    }
+
+    fn refresh_token(&mut self, fields: &[(String, String)]) -> Response {
+        let refresh_token = field(fields, "refresh_token").unwrap_or_default();
+        match self.sessions.iter().position(|session| session.refresh_token.as_deref() == Some(refresh_token)) {
+            Some(i) => self.issue_tokens(i),
+            None => oauth_error(400, "invalid_grant", "Unknown refresh_token"),
+        }
+    }
+
+    /// Revoking either token ends the session (unknown tokens are fine)
+    fn revoke(&mut self, request: &Request) -> Response {
+        let fields = http::parse_params(&request.body);
+        let token = field(&fields, "token").unwrap_or_default();
+        self.sessions.retain(|session| {
+            session.token != token && session.refresh_token.as_deref() != Some(token)
+        });
+        Response::json(200, json!({}))
+    }
+
+    /// The token response (expires_in is in seconds for OAuth 2.0)
+    fn issue_tokens(&mut self, i: usize) -> Response {
+        let mut fields = self.renew_tokens(i);
+        if let Some(expires_in_ms) = fields.remove("expires_in_ms").and_then(|v| v.as_u64()) {
+            fields.insert("expires_in".to_string(), json!(expires_in_ms / 1000));
+        }
+        fields.insert("token_type".to_string(), json!("Bearer"));
+        Response::json(200, Value::Object(fields))
+    }
+}
+
+/// Handle a request for the issuer (the path after ISSUER_PREFIX)
+pub fn route(homeserver: &mut Homeserver, request: &Request, endpoint: &str) -> Response {
+    match (request.method.as_str(), endpoint) {
+        ("GET", ".well-known/openid-configuration") => {
+            Response::json(200, homeserver.metadata(request))
+        },
+        ("POST", "registration") => homeserver.register_client(request),
+        ("POST", "device") => homeserver.device_authorization(request),
+        ("GET", "device") => homeserver.verify_device(request),
+        ("POST", "token") => homeserver.token(request),
+        ("POST", "revoke") => homeserver.revoke(request),
+        _ => {
+            oauth_error(404, "not_found", "Unrecognized request")
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! User-interactive authentication
+//!
+//! Tracks the stages completed in each UIA session: the request
+//! succeeds once the stages of one of the flows are all completed.
+
+use ureq::serde_json::{json, Value};
+
+use crate::http::Response;
+use super::Homeserver;
+
+/// A user-interactive authentication session in progress
+#[derive(Debug)]
+pub struct UiaSession {
+    session: String,
+    completed: Vec<String>,
+}
+
+impl Homeserver {
+    /// Check the auth of the request body (completing one more stage),
+    /// returns the 401 response describing what is left to do. The
+    /// password stage must authenticate user_id (if known).
+    pub(super) fn interactive_auth(&mut self, body: &Value, flows: &[Vec<String>],
+                                   params: Value, user_id: Option<&str>) -> Result<(), Response> {
+        let auth = body.get("auth");
+        let session = auth.and_then(|auth| auth.get("session")).and_then(Value::as_str);
+        let i = match session.and_then(|session| self.uia_sessions.iter().position(|s| s.session == session)) {
+            Some(i) => i,
+            None => {
+                let session = self.gen_id("uia");
+                self.uia_sessions.push(UiaSession { session, completed: Vec::new() });
+                self.uia_sessions.len() - 1
+            }
+        };
+        let mut error = None;
+        if let Some(auth) = auth {
+            let stage = auth.get("type").and_then(Value::as_str).unwrap_or_default();
+            if ! flows.iter().any(|flow| flow.iter().any(|s| s == stage)) {
+                error = Some(format!("Unexpected stage {}", stage));
+            } else if let Err(e) = self.check_stage(stage, auth, user_id) {
+                error = Some(e);
+            } else if ! self.uia_sessions[i].completed.iter().any(|s| s == stage) {
+                self.uia_sessions[i].completed.push(stage.to_string());
+            }
+        }
+        let completed = &self.uia_sessions[i].completed;
+        if flows.iter().any(|flow| flow.iter().all(|stage| completed.contains(stage))) {
+            self.uia_sessions.remove(i);
+            return Ok(());
+        }
+        let flows: Vec<Value> = flows.iter().map(|stages| json!({ "stages": stages })).collect();
+        let mut info = json!({
+            "session": self.uia_sessions[i].session,
+            "flows": flows,
+            "params": params,
+            "completed": completed,
+        });
+        if let Some(error) = error {
+            info["errcode"] = json!("M_FORBIDDEN");
+            info["error"] = json!(error);
+        }
+        Err(Response::json(401, info))
+    }
+
+    /// Returns the error if the stage was not completed
+    fn check_stage(&self, stage: &str, auth: &Value, user_id: Option<&str>) -> Result<(), String> {
+        match stage {
+            "m.login.password" => {
+                let user = auth.get("identifier")
+                    .and_then(|identifier| identifier.get("user"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let password = auth.get("password").and_then(Value::as_str).unwrap_or_default();
+                let auth_user_id = self.user_id(user);
+                let valid = (user_id.is_none() || user_id == Some(auth_user_id.as_str()))
+                    && self.users.iter().any(|u| self.user_id(&u.user) == auth_user_id && u.password == password);
+                if valid {
+                    Ok(())
+                } else {
+                    Err("Invalid username or password".to_string())
+                }
+            },
+            "m.login.dummy" | "m.login.terms" => Ok(()),
+            "m.login.registration_token" => {
+                let token = auth.get("token").and_then(Value::as_str);
+                let expected = self.registration.as_ref().and_then(|registration| registration.token.as_deref());
+                if token.is_some() && token == expected {
+                    Ok(())
+                } else {
+                    Err("Invalid registration token".to_string())
+                }
+            },
+            _ => Err(format!("Unsupported stage {}", stage)),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Minimal HTTP/1.1 handling
+//!
+//! Just enough HTTP for mtxcli: one request per connection,
+//! bodies delimited by Content-Length.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use ureq::serde_json::Value;
+
+/// Largest request body we are willing to read
+const MAX_BODY: usize = 1024 * 1024;
+
+/// An HTTP request
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// Returns the (first) header named name (case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the query parameter named name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the bearer token from the Authorization header
+    /// (or the deprecated access_token query parameter)
+    pub fn token(&self) -> Option<&str> {
+        match self.header("Authorization") {
+            Some(authorization) => authorization.strip_prefix("Bearer "),
+            None => self.param("access_token"),
+        }
+    }
+
+    /// Returns the body as JSON (Null if absent or invalid)
+    pub fn json(&self) -> Value {
+        ureq::serde_json::from_str(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+/// An HTTP response
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn html(status: u16, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/html",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Redirect the browser to location
+    pub fn redirect(location: &str) -> Self {
+        Response {
+            status: 302,
+            content_type: "text/html",
+            headers: vec![("Location".to_string(), location.to_string())],
+            body: String::new(),
+        }
+    }
+
+    /// A standard Matrix error response
+    pub fn error(status: u16, errcode: &str, error: &str) -> Self {
+        let mut body = ureq::serde_json::Map::new();
+        body.insert("errcode".to_string(), Value::String(errcode.to_string()));
+        body.insert("error".to_string(), Value::String(error.to_string()));
+        Response::json(status, Value::Object(body))
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        302 => "Found",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        _ => "Unknown",
+    }
+}
+
+/// URL decode a path
+pub fn decode_path(v: &str) -> String {
+    percent_encoding::percent_decode_str(v)
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+/// URL decode a query (or form) component
+pub fn decode(v: &str) -> String {
+    decode_path(&v.replace('+', " "))
+}
+
+/// Parse key=value&key=value
+pub fn parse_params(params: &str) -> Vec<(String, String)> {
+    params.split('&')
+        .filter(|param| param.len() > 0)
+        .map(|param| {
+            match param.find('=') {
+                Some(i) => (decode(&param[..i]), decode(&param[i + 1..])),
+                None => (decode(param), String::new()),
+            }
+        })
+        .collect()
+}
+
+/// Reads one request from the stream
+pub fn read_request(stream: &TcpStream) -> Result<Request, Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.trim_end().split(' ');
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    if method.len() == 0 || target.len() == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid request line"));
+    }
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.len() == 0 {
+            break;
+        }
+        if let Some(i) = header.find(':') {
+            headers.push((header[..i].trim().to_string(), header[i + 1..].trim().to_string()));
+        }
+    }
+    let content_length = headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if content_length > MAX_BODY {
+        return Err(Error::new(ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    let (path, query) = match target.find('?') {
+        Some(i) => (decode_path(&target[..i]), parse_params(&target[i + 1..])),
+        None => (decode_path(&target), Vec::new()),
+    };
+    Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+/// Writes the response (and closes the connection)
+pub fn write_response(mut stream: &TcpStream, response: &Response) -> Result<(), Error> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    head.push_str(&format!("Content-Type: {}\r\n", response.content_type));
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    for (key, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
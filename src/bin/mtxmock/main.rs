@@ -0,0 +1,99 @@
+//! **mtxmock**
+//!
+//! Mock Matrix homeserver for testing mtxcli
+//!
+//! Serves the client-server endpoints used by mtxcli over plain HTTP on
+//! the loopback interface, with users and rooms from a JSON script.
+
+use std::fs::File;
+use std::io::Write;
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::Parser;
+
+mod homeserver;  use homeserver::{Homeserver, Script};
+mod http;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Mock Matrix homeserver for testing mtxcli", long_about = None)]
+pub struct Args {
+    /// port to listen on (0 picks a free port)
+    #[arg(short, long, default_value_t = 8008)]
+    port: u16,
+
+    /// JSON script with the users and rooms
+    #[arg(short, long)]
+    script: Option<String>,
+
+    /// server name (when there is no script)
+    #[arg(long, default_value = "localhost")]
+    server_name: String,
+
+    /// access tokens of clients using refresh tokens expire after this (ms)
+    #[arg(long)]
+    token_lifetime_ms: Option<u64>,
+
+    /// print each request
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+fn load_script(filename: &str) -> Script {
+    let file = File::open(filename).unwrap_or_else(|e| {
+        eprintln!("cannot open script {}: {}", filename, e);
+        process::exit(1);
+    });
+    ureq::serde_json::from_reader(file).unwrap_or_else(|e| {
+        eprintln!("invalid script {}: {}", filename, e);
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut script = match &args.script {
+        Some(filename) => load_script(filename),
+        None => Script::new(&args.server_name),
+    };
+    if args.token_lifetime_ms.is_some() {
+        script.token_lifetime_ms = args.token_lifetime_ms;
+    }
+    let listener = TcpListener::bind(("127.0.0.1", args.port)).unwrap_or_else(|e| {
+        eprintln!("cannot listen on port {}: {}", args.port, e);
+        process::exit(1);
+    });
+    // tests read this line to find the port
+    println!("listening on http://{}", listener.local_addr().unwrap());
+    std::io::stdout().flush().ok();
+    let homeserver = Arc::new(Mutex::new(Homeserver::new(script)));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let homeserver = Arc::clone(&homeserver);
+        let verbose = args.verbose;
+        thread::spawn(move || {
+            match http::read_request(&stream) {
+                Ok(request) => {
+                    let response = homeserver::handle(&homeserver, &request);
+                    if verbose > 0 {
+                        eprintln!("{} {} => {}", request.method, request.path, response.status);
+                    }
+                    if let Err(e) = http::write_response(&stream, &response) {
+                        eprintln!("write failed: {}", e);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("invalid request: {}", e);
+                }
+            }
+        });
+    }
+}
@@ -5,24 +5,54 @@
 
 use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write, Error, ErrorKind};
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read, Write, Error, ErrorKind};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
+use flexi_logger::{FlexiLoggerError, LogSpecBuilder, LogSpecification};
+use log::LevelFilter;
+use zeroize::Zeroizing;
 
+mod error;       use error::MatrixError;
 mod interactive;
 mod migrations;  use migrations::run_migrations;
+mod oidc;
 mod parking;
+mod rooms;       use rooms::Rooms;
+mod secrets;     use secrets::SecretStore;
+mod sso;         use sso::SsoListener;
 mod system;      use system::System;
-mod url;
-mod web;
+mod tls;
+mod trace;
+mod transport;   use transport::MatrixTransport;
+mod uia;
+mod url;         use url::ClientApi;
+mod web;         use web::{Http, HttpConfig, RetryPolicy, RoomOptions};
 
+const DEVICE_ID_KEY: &str = "_device_id";
+const DEVICE_NAME_KEY: &str = "device_name";
 const FILTER_KEY: &str = "_filter";
+const OIDC_CLIENT_ID_KEY: &str = "_oidc_client_id";
+const OIDC_ISSUER_KEY: &str = "_oidc_issuer";
+const OIDC_TOKEN_ENDPOINT_KEY: &str = "_oidc_token_endpoint";
 const PASSWORD_KEY: &str = "password";
+const PIN_KEY: &str = "pin_sha256";
+const PROXY_KEY: &str = "proxy";
+const REMEMBER_PASSWORD_KEY: &str = "remember_password";
+const CA_FILE_KEY: &str = "ca_file";
+const CONNECT_TIMEOUT_KEY: &str = "connect_timeout_ms";
+const READ_TIMEOUT_KEY: &str = "read_timeout_ms";
+const SYNC_TIMEOUT_KEY: &str = "sync_timeout_ms";
+const TRACE_HTTP_KEY: &str = "trace_http";
+const RETRY_MAX_ATTEMPTS_KEY: &str = "retry_max_attempts";
+const RETRY_MAX_DELAY_KEY: &str = "retry_max_delay_ms";
 const ROOM_ID_KEY: &str = "_room_id";
 const ROOM_KEY: &str = "room";
 const SINCE_KEY: &str = "_since";
 const SERVER_KEY: &str = "server";
+const REFRESH_TOKEN_KEY: &str = "_refresh_token";
 const TOKEN_KEY: &str = "_token";
 const USER_KEY: &str = "user";
 const USERNAME_KEY: &str = "username";
@@ -33,7 +63,9 @@ const HTTPS: &str = "https://";
 const SERVER_MATRIX: &str = "https://matrix.org";
 
 const EMPTY: &str = "";
-const MTX_TIMEOUT: i32 = 300; // ms
+
+/// How many times we ask for the passphrase of the secret store
+const PASSPHRASE_ATTEMPTS: usize = 3;
 
 #[derive(Parser,Default,Debug,PartialEq)]
 #[command(author, version, about, long_about = None)]
@@ -43,6 +75,10 @@ pub struct Args {
 
     #[arg(short, long)]
     parking: bool,
+
+    /// log HTTP requests and responses (secrets are redacted)
+    #[arg(long)]
+    trace_http: bool,
 }
 
 /// Action for the program to act upon
@@ -59,7 +95,7 @@ pub enum Action {
 }
 
 /// Config struct
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct Mtxcli {
     pub qualifier: &'static str,
     pub organization: &'static str,
@@ -71,11 +107,22 @@ pub struct Mtxcli {
     pub user: String,
     pub username: String,
     pub server: String,
+    /// client-server API path prefix negotiated with the server
+    pub api_prefix: String,
     token: String,
+    /// only written to the config if remember_password is set
+    password: String,
     pub logged_in: bool,
+    /// the room chat lines are sent to
     pub room_id: String,
+    /// all the joined rooms (followed by sync)
+    rooms: Rooms,
     pub filter: String,
     pub since: String,
+    pub http_config: HttpConfig,
+    transport: Box<dyn MatrixTransport>,
+    /// the password and tokens (encrypted at rest)
+    secrets: SecretStore,
 }
 
 /// implementation of Mtxcli
@@ -84,10 +131,27 @@ impl Mtxcli {
     /// Construct a new Mtxcli
     pub fn new(qualifier: &'static str, organization: &'static str,
                app: &'static str, version: &'static str) -> Self {
-        let system = System::new(qualifier, organization, app);
+        let config_dir = System::new(qualifier, organization, app).config_dir;
+        let transport = Box::new(Http::new(HttpConfig::default())
+                                 .expect("cannot initialize http"));
+        let mut mtxcli = Mtxcli::with_transport(qualifier, organization, app, version,
+                                                &config_dir, transport);
+        mtxcli.args = Args::parse();
+        mtxcli
+    }
+
+    /// Construct a Mtxcli which talks to the homeserver through the
+    /// transport (e.g. a ScriptedTransport) and keeps its configuration
+    /// in config_dir (without looking at the command line)
+    pub fn with_transport(qualifier: &'static str, organization: &'static str,
+                          app: &'static str, version: &'static str, config_dir: &str,
+                          transport: Box<dyn MatrixTransport>) -> Self {
+        let mut system = System::new(qualifier, organization, app);
+        system.config_dir = config_dir.to_string();
         let action = Action::Default;
-        let args = Args::parse();
+        let args = Args::default();
         let logged_in = false;
+        let secrets = SecretStore::new(Path::new(&system.config_dir));
         Mtxcli {
             qualifier,
             organization,
@@ -99,17 +163,36 @@ impl Mtxcli {
             user: EMPTY.to_string(),
             username: USER_KEY.to_string(),
             server: SERVER_MATRIX.to_string(),
+            api_prefix: EMPTY.to_string(),
             token: EMPTY.to_string(),
+            password: EMPTY.to_string(),
             logged_in,
             room_id: EMPTY.to_string(),
+            rooms: Rooms::default(),
             filter: EMPTY.to_string(),
             since: EMPTY.to_string(),
+            http_config: HttpConfig::default(),
+            transport,
+            secrets,
         }
     }
 
     /// Parse command line arguments
     pub fn parse_args(&mut self) {
-        flexi_logger::Logger::try_with_env().unwrap()
+        // HTTP traces are always enabled in the logger, but only
+        // written when trace_http is set
+        let env_spec = LogSpecification::env().unwrap_or_else(|e| {
+            // keep the valid parts of RUST_LOG
+            eprintln!("invalid RUST_LOG: {}", e);
+            match e {
+                FlexiLoggerError::Parse(_, spec) => spec,
+                _ => LogSpecification::off(),
+            }
+        });
+        let spec = LogSpecBuilder::from_module_filters(env_spec.module_filters())
+            .module(trace::HTTP_TRACE, LevelFilter::Trace)
+            .build();
+        flexi_logger::Logger::with(spec)
             .start()
             .expect("cannot initialize flexi_logger");
         self.args = Args::parse();
@@ -130,6 +213,9 @@ impl Mtxcli {
         self.room_id = self.get_default(ROOM_ID_KEY, EMPTY);
         self.filter = self.get_default(FILTER_KEY, EMPTY);
         self.since = self.get_default(SINCE_KEY, EMPTY);
+        // asks for the passphrase of the secret store (if there is one)
+        self.password = self.get_default(PASSWORD_KEY, EMPTY);
+        self.set_http();
         match self.action {
             Action::ParkingLot => parking::act(self),
             _ =>  interactive::act(self)
@@ -141,21 +227,42 @@ impl Mtxcli {
             Err(Error::new(ErrorKind::PermissionDenied,
                            "may not set a variable beginning with __ "))
         } else {
-            let mut keypath = PathBuf::new();
-            keypath.push(&self.system.config_dir);
-            std::fs::create_dir_all(&keypath)?;
-            keypath.push(key);
-            File::create(keypath)?.write_all(value.as_bytes())?;
+            // store_password decides whether the password is written
+            if key != PASSWORD_KEY {
+                self.write_key(key, value)?;
+            }
             match key { // special case side effects
                 USER_KEY => { self.set_user(value); }
-                PASSWORD_KEY => { self.set_password(); }
+                SERVER_KEY => { self.set_server(value); }
+                PASSWORD_KEY => { self.set_password(value); }
+                REMEMBER_PASSWORD_KEY => { self.set_remember_password(); }
                 ROOM_KEY => { self.set_room(); }
+                CONNECT_TIMEOUT_KEY | READ_TIMEOUT_KEY | SYNC_TIMEOUT_KEY
+                    | RETRY_MAX_ATTEMPTS_KEY | RETRY_MAX_DELAY_KEY
+                    | PROXY_KEY | CA_FILE_KEY | PIN_KEY | TRACE_HTTP_KEY => { self.set_http(); }
                 _ => { }
             }
             Ok(())
         }
     }
 
+    // store the value without any side effects
+    fn write_key(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        if Self::is_secret(key) {
+            self.set_secret(key, value)
+        } else {
+            self.write_file(key, value)
+        }
+    }
+
+    fn write_file(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let mut keypath = PathBuf::new();
+        keypath.push(&self.system.config_dir);
+        std::fs::create_dir_all(&keypath)?;
+        keypath.push(key);
+        File::create(keypath)?.write_all(value.as_bytes())
+    }
+
     pub fn set_user(&mut self, value: &str) {
         debug!("# USER_KEY set '{}' = '{}'", USER_KEY, value);
         let i = match value.find('@') {
@@ -168,8 +275,18 @@ impl Mtxcli {
         };
         self.username = (&value[i..j]).to_string();
         if j < value.len() {
-            self.server = String::from(HTTPS);
-            self.server.push_str(&value[j + 1..]);
+            let server_name = &value[j + 1..];
+            self.server = match web::discover_homeserver(&*self.transport, server_name) {
+                Ok(base_url) => {
+                    base_url
+                },
+                Err(e) => {
+                    debug!("# discovery failed for '{}': {}", server_name, e);
+                    let mut server = String::from(HTTPS);
+                    server.push_str(server_name);
+                    server
+                }
+            };
         } else {
             self.server = SERVER_MATRIX.to_string();
         }
@@ -177,20 +294,77 @@ impl Mtxcli {
         debug!("# user = '{}' username = '{}' server = '{}'", self.user, self.username, self.server);
         self.set(USERNAME_KEY, &self.username.clone()).unwrap();
         self.set(SERVER_KEY, &self.server.clone()).unwrap();
-        self.unset(TOKEN_KEY).unwrap();
-        self.token = EMPTY.to_string();
+        self.clear_tokens();
+        self.unset(DEVICE_ID_KEY).unwrap();
+    }
+
+    pub fn set_server(&mut self, value: &str) {
+        self.server = value.to_string();
+        self.api_prefix = EMPTY.to_string();
     }
 
-    pub fn set_password(&mut self) {
+    pub fn set_password(&mut self, value: &str) {
         debug!("# PASSWORD_KEY set '{}' => clearing TOKEN_KEY", PASSWORD_KEY);
-        self.unset(TOKEN_KEY).unwrap();
+        self.store_password(value);
+        self.clear_tokens();
+    }
+
+    pub fn set_remember_password(&mut self) {
+        let password = self.password.clone();
+        self.store_password(&password);
+    }
+
+    // keep the password in memory, and in the config only if remember_password
+    fn store_password(&mut self, password: &str) {
+        self.password = password.to_string();
+        if password.len() > 0 && self.get_bool(REMEMBER_PASSWORD_KEY) {
+            self.write_key(PASSWORD_KEY, password).unwrap();
+        } else {
+            self.unset(PASSWORD_KEY).unwrap();
+        }
+    }
+
+    // once logged in only the access token is kept (unless remember_password)
+    fn forget_password(&mut self) {
+        if ! self.get_bool(REMEMBER_PASSWORD_KEY) {
+            debug!("# forgetting the password");
+            self.store_password(EMPTY);
+        }
     }
 
+    // the filter (and since) cover all the joined rooms: only the
+    // room chat lines are sent to changes
     pub fn set_room(&mut self) {
-        debug!("# ROOM_KEY set '{}' => clearing ROOM_ID_KEY, SINCE_KEY, FILTER_KEY", ROOM_KEY);
+        debug!("# ROOM_KEY set '{}' => clearing ROOM_ID_KEY", ROOM_KEY);
         self.unset(ROOM_ID_KEY).unwrap();
-        self.unset(SINCE_KEY).unwrap();
-        self.unset(FILTER_KEY).unwrap();
+        self.room_id = EMPTY.to_string();
+    }
+
+    pub fn set_http(&mut self) {
+        let max_attempts = self.get_number(RETRY_MAX_ATTEMPTS_KEY, web::RETRY_MAX_ATTEMPTS as u64);
+        let max_delay = self.get_number(RETRY_MAX_DELAY_KEY, web::RETRY_MAX_DELAY);
+        let config = HttpConfig {
+            connect_timeout: Duration::from_millis(self.get_number(CONNECT_TIMEOUT_KEY, web::CONNECT_TIMEOUT)),
+            read_timeout: Duration::from_millis(self.get_number(READ_TIMEOUT_KEY, web::READ_TIMEOUT)),
+            sync_timeout: Duration::from_millis(self.get_number(SYNC_TIMEOUT_KEY, web::SYNC_TIMEOUT)),
+            retry: RetryPolicy::new(max_attempts.max(1) as u32, max_delay),
+            proxy: self.get_option(PROXY_KEY),
+            ca_file: self.get_option(CA_FILE_KEY),
+            pin: self.get_option(PIN_KEY),
+            trace: self.args.trace_http || self.get_bool(TRACE_HTTP_KEY),
+        };
+        let mut logged = config.clone();
+        logged.proxy = logged.proxy.as_deref().map(trace::redact_url);
+        debug!("# http = {:?}", logged);
+        match self.transport.configure(&config) {
+            Ok(()) => {
+                self.http_config = config;
+            },
+            Err(e) => {
+                self.prompt();
+                println!("error: {}", e);
+            }
+        }
     }
 
     pub fn unset(&mut self, key: &str) -> Result<(), Error> {
@@ -198,33 +372,152 @@ impl Mtxcli {
             Err(Error::new(ErrorKind::PermissionDenied,
                            "may not unset a variable beginning with __ "))
         } else {
-            let mut keypath = PathBuf::new();
-            keypath.push(&self.system.config_dir);
-            std::fs::create_dir_all(&keypath)?;
-            keypath.push(key);
-            if std::fs::metadata(&keypath).is_ok() { // keypath exists
-                std::fs::remove_file(keypath)?;
+            if Self::is_secret(key) {
+                self.remove_secret(key)?;
+            } else {
+                self.remove_file(key)?;
+            }
+            match key { // special case side effects
+                CONNECT_TIMEOUT_KEY | READ_TIMEOUT_KEY | SYNC_TIMEOUT_KEY
+                    | RETRY_MAX_ATTEMPTS_KEY | RETRY_MAX_DELAY_KEY
+                    | PROXY_KEY | CA_FILE_KEY | PIN_KEY | TRACE_HTTP_KEY => { self.set_http(); }
+                REMEMBER_PASSWORD_KEY => { self.set_remember_password(); }
+                _ => { }
             }
             Ok(())
         }
     }
 
+    fn remove_file(&mut self, key: &str) -> Result<(), Error> {
+        let mut keypath = PathBuf::new();
+        keypath.push(&self.system.config_dir);
+        std::fs::create_dir_all(&keypath)?;
+        keypath.push(key);
+        if std::fs::metadata(&keypath).is_ok() { // keypath exists
+            std::fs::remove_file(keypath)?;
+        }
+        Ok(())
+    }
+
     pub fn get(&mut self, key: &str) -> Result<Option<String>, Error> {
         if key.eq(CURRENT_VERSION_KEY) {
             Ok(Some(self.version.to_string()))
+        } else if Self::is_secret(key) {
+            self.get_secret(key)
         } else {
-            let mut keypath = PathBuf::new();
-            keypath.push(&self.system.config_dir);
-            std::fs::create_dir_all(&keypath)?;
-            keypath.push(key);
-            if let Ok(mut file)= File::open(keypath) {
-                let mut value = String::new();
-                file.read_to_string(&mut value)?;
-                Ok(Some(value))
-            } else {
-                Ok(None)
+            self.read_file(key)
+        }
+    }
+
+    fn read_file(&mut self, key: &str) -> Result<Option<String>, Error> {
+        let mut keypath = PathBuf::new();
+        keypath.push(&self.system.config_dir);
+        std::fs::create_dir_all(&keypath)?;
+        keypath.push(key);
+        if let Ok(mut file)= File::open(keypath) {
+            let mut value = String::new();
+            file.read_to_string(&mut value)?;
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // the password and tokens are kept in the secret store
+    fn is_secret(key: &str) -> bool {
+        matches!(key, PASSWORD_KEY | TOKEN_KEY | REFRESH_TOKEN_KEY)
+    }
+
+    /// Unlock the secret store (creating it if there is none yet):
+    /// if the user declines secrets are not saved this session
+    pub fn unlock_secrets(&mut self) {
+        if ! self.secrets.needs_unlock() {
+            return;
+        }
+        if self.secrets.exists() {
+            for _ in 0..PASSPHRASE_ATTEMPTS {
+                let passphrase = match self.ask_secret("passphrase:").map(Zeroizing::new) {
+                    Some(passphrase) if passphrase.len() > 0 => passphrase,
+                    _ => break,
+                };
+                match self.secrets.unlock(&passphrase) {
+                    Ok(()) => {
+                        return;
+                    },
+                    Err(e) => {
+                        self.prompt();
+                        println!("cannot unlock the secret store: {}", e);
+                        if e.kind() != ErrorKind::PermissionDenied {
+                            break;
+                        }
+                    }
+                }
+            }
+        } else {
+            self.prompt();
+            println!("choose a passphrase to encrypt your password and tokens");
+            let passphrase = Zeroizing::new(self.ask_secret("new passphrase:").unwrap_or_default());
+            if passphrase.len() > 0 {
+                let again = self.ask_secret("new passphrase again:").map(Zeroizing::new);
+                if again.as_deref().map(String::as_str) == Some(passphrase.as_str()) {
+                    match self.secrets.create(&passphrase) {
+                        Ok(()) => {
+                            return;
+                        },
+                        Err(e) => {
+                            self.prompt();
+                            println!("cannot create the secret store: {}", e);
+                        }
+                    }
+                } else {
+                    self.prompt();
+                    println!("the passphrases do not match");
+                }
+            }
+        }
+        self.secrets.decline();
+        self.prompt();
+        println!("the secret store is locked: secrets will not be saved this session");
+    }
+
+    fn get_secret(&mut self, key: &str) -> Result<Option<String>, Error> {
+        if self.secrets.exists() {
+            self.unlock_secrets();
+        }
+        match self.secrets.get(key) {
+            Some(value) => Ok(Some(value)),
+            None => self.migrate_secret(key),
+        }
+    }
+
+    fn set_secret(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        self.unlock_secrets();
+        self.secrets.set(key, value)?;
+        // never leave an old plaintext copy behind
+        self.remove_file(key)
+    }
+
+    fn remove_secret(&mut self, key: &str) -> Result<(), Error> {
+        if self.secrets.exists() {
+            self.unlock_secrets();
+        }
+        self.secrets.remove(key)?;
+        self.remove_file(key)
+    }
+
+    // move a plaintext secret (from before 0.7.0) into the secret store,
+    // keeping the file if the store stays locked
+    fn migrate_secret(&mut self, key: &str) -> Result<Option<String>, Error> {
+        let value = self.read_file(key)?;
+        if let Some(value) = &value {
+            self.unlock_secrets();
+            self.secrets.set(key, value)?;
+            if self.secrets.is_unlocked() {
+                debug!("# moved {} into the secret store", key);
+                self.remove_file(key)?;
             }
         }
+        Ok(value)
     }
 
     pub fn get_default(&mut self, key: &str, default: &str) -> String {
@@ -242,113 +535,751 @@ impl Mtxcli {
         }
     }
 
+    pub fn get_option(&mut self, key: &str) -> Option<String> {
+        let value = self.get_default(key, EMPTY);
+        let value = value.trim();
+        if value.len() == 0 {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    }
+
+    pub fn get_bool(&mut self, key: &str) -> bool {
+        let value = self.get_default(key, EMPTY);
+        matches!(value.trim(), "true" | "yes" | "on" | "1")
+    }
+
+    pub fn get_number(&mut self, key: &str, default: u64) -> u64 {
+        let value = self.get_default(key, EMPTY);
+        if value.len() == 0 {
+            default
+        } else {
+            match value.trim().parse::<u64>() {
+                Ok(number) => number,
+                Err(e) => {
+                    error!("invalid number for key {}: '{}': {:?}", key, value, e);
+                    default
+                }
+            }
+        }
+    }
+
+    /// URL builder for the client-server API
+    pub fn api(&self) -> ClientApi {
+        ClientApi::new(&self.server, &self.api_prefix)
+    }
+
     pub fn prompt(&self) {
         print!("{}> ", self.app);
     }
 
+    /// Ask for a secret such as a password (not echoed on a terminal)
+    pub fn ask_secret(&self, question: &str) -> Option<String> {
+        self.prompt();
+        print!("{} ", question);
+        io::stdout().flush().ok();
+        if io::stdin().is_terminal() {
+            read_password()
+        } else {
+            read_secret_line()
+        }
+    }
+
+    /// Ask the user a question, returns the answer (None at end of input)
+    pub fn ask(&self, question: &str) -> Option<String> {
+        self.prompt();
+        print!("{} ", question);
+        io::stdout().flush().ok();
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(n) if n > 0 => Some(answer.trim().to_string()),
+            _ => None,
+        }
+    }
+
     pub fn user_says(&mut self, text: &str) {
         if ! self.logged_in {
-            if ! self.login() {
+            if self.login().is_err() {
                 self.prompt();
                 println!("error: not connected");
                 return;
             }
         }
-        if self.room_id.len() == 0 {
-            if ! self.get_room_id() {
-                self.prompt();
-                println!("error: could not find room_id");
-                return;
-            }
-        }
         if self.filter.len() == 0 {
-            if ! self.get_filter() {
+            if let Err(e) = self.with_session(Self::get_filter) {
                 self.prompt();
-                println!("error: could not create filter");
+                println!("error: could not create filter: {}", e);
                 return;
             }
         }
-        self.read_messages();
+        if let Err(e) = self.with_session(Self::load_rooms) {
+            self.prompt();
+            println!("error: could not list rooms: {}", e);
+            return;
+        }
+        if let Err(e) = self.with_session(Self::read_messages) {
+            self.prompt();
+            println!("error: could not read messages: {}", e);
+        }
         if text.len() > 0 {
-            if web::send_message(&self.server, &self.room_id, &text, &self.token) {
-                // The following is not required, because we will get what
-                // the user said when we read_messages
-                // println!("{}> {}", self.username, text);
-                self.read_messages(); // update since to include what user said
-            } else {
-                println!("{}> {} # FAILED TO SEND", self.username, text);
+            if self.room_id.len() == 0 {
+                if let Err(e) = self.with_session(Self::get_room_id) {
+                    self.prompt();
+                    println!("error: could not find room_id: {}", e);
+                    return;
+                }
+            }
+            match self.with_session(|mtxcli| mtxcli.send_message(text)) {
+                Ok(()) => {
+                    // The following is not required, because we will get what
+                    // the user said when we read_messages
+                    // println!("{}> {}", self.username, text);
+                    // update since to include what user said
+                    if let Err(e) = self.with_session(Self::read_messages) {
+                        self.prompt();
+                        println!("error: could not read messages: {}", e);
+                    }
+                },
+                Err(e) => {
+                    println!("{}> {} # FAILED TO SEND: {}", self.username, text, e);
+                }
             }
         } // else just update
     }
 
-    pub fn login(&mut self) -> bool {
+    /// Do the operation, logging in again (and retrying once) if the
+    /// server has invalidated our access token
+    fn with_session<T, F>(&mut self, operation: F) -> Result<T, MatrixError>
+    where F: Fn(&mut Self) -> Result<T, MatrixError> {
+        match operation(self) {
+            Err(e) if e.is_logged_out() => {
+                self.recover_session(&e)?;
+                operation(self)
+            },
+            result => result
+        }
+    }
+
+    /// Renew the invalidated token with the refresh token, else forget
+    /// it and login with the password (if set)
+    fn recover_session(&mut self, e: &MatrixError) -> Result<(), MatrixError> {
+        debug!("access token invalidated: {}", e);
+        match self.refresh() {
+            Ok(()) => {
+                return Ok(());
+            },
+            Err(e) => {
+                debug!("cannot refresh the access token: {}", e);
+            }
+        }
+        self.clear_tokens();
+        self.logged_in = false;
+        self.prompt();
+        println!("session expired: {}", e);
+        if self.password.len() == 0 {
+            self.prompt();
+            println!("please /password (then /login again)");
+            return Err(e.clone());
+        }
+        self.login()
+    }
+
+    pub fn login(&mut self) -> Result<(), MatrixError> {
         self.prompt();
         println!("logging in...");
+        let result = self.authenticate();
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("logged in");
+            },
+            Err(e) => {
+                println!("authentication failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // pick the client API version (once per server)
+    fn negotiate_api(&mut self) -> Result<(), MatrixError> {
+        if self.api_prefix.len() == 0 {
+            let versions = web::get_versions(&*self.transport, &self.server)?;
+            self.api_prefix = web::negotiate_prefix(&versions).to_string();
+            debug!("versions = {:?} => api_prefix = {}", versions, self.api_prefix);
+        }
+        Ok(())
+    }
+
+    // try the stored token first, else login with the password
+    fn authenticate(&mut self) -> Result<(), MatrixError> {
+        self.negotiate_api()?;
         self.token = self.get_default(TOKEN_KEY, EMPTY);
         self.logged_in = false;
         if self.token.len() > 0 {
-            if web::whoami(&self.server, &self.token) {
-                self.logged_in = true;
+            match web::whoami(&*self.transport, &self.api(), &self.token) {
+                Ok(whoami) => {
+                    self.save_device_id(whoami.device_id.as_deref());
+                    self.logged_in = true;
+                    return Ok(());
+                },
+                Err(e) if e.is_unknown_token() => {
+                    debug!("stored token is no longer valid: {}", e);
+                    if self.refresh().is_ok() {
+                        return Ok(());
+                    }
+                },
+                Err(e) => {
+                    return Err(e);
+                }
             }
         }
-        if ! self.logged_in {
-            if web::get_login_type(&self.server) {
-                let user = self.get_default(USER_KEY, USER_KEY);
-                let password = self.get_default(PASSWORD_KEY, EMPTY);
-                if password.len() == 0 {
-                    self.prompt();
-                    println!("please /set user @USER:matrix.org");
-                    self.prompt();
-                    println!("please /set password my-password");
+        let login_types = web::get_login_types(&*self.transport, &self.api())?;
+        if ! login_types.iter().any(|login_type| login_type == web::MTX_LOGIN_PASSWORD) {
+            if oidc::get_auth_metadata(&*self.transport, &self.server).is_ok() {
+                self.prompt();
+                println!("please /login oidc");
+            } else if login_types.iter().any(|login_type| login_type == web::MTX_LOGIN_SSO) {
+                self.prompt();
+                println!("please /login sso");
+            }
+            return Err(MatrixError::Config(format!("server does not support {}",
+                                                   web::MTX_LOGIN_PASSWORD)));
+        }
+        let user = self.get_default(USER_KEY, USER_KEY);
+        let password = self.password.clone();
+        if password.len() == 0 {
+            self.prompt();
+            println!("please /set user @USER:matrix.org");
+            self.prompt();
+            println!("please /password");
+            return Err(MatrixError::Config("password is not set".to_string()));
+        }
+        let device = self.device();
+        let response = web::authenticate_user(&*self.transport, &self.api(), &user, &password, &device)?;
+        self.save_device_id(response.device_id.as_deref());
+        self.clear_tokens();
+        self.save_tokens(&response.access_token, response.refresh_token.as_deref());
+        self.forget_password();
+        Ok(())
+    }
+
+    pub fn login_sso(&mut self) -> Result<(), MatrixError> {
+        self.prompt();
+        println!("logging in with SSO...");
+        let result = self.authenticate_sso();
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("logged in as {}", self.user);
+            },
+            Err(e) => {
+                println!("authentication failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // login in the browser, which hands us a login token via the loopback
+    fn authenticate_sso(&mut self) -> Result<(), MatrixError> {
+        self.negotiate_api()?;
+        self.logged_in = false;
+        let login_types = web::get_login_types(&*self.transport, &self.api())?;
+        if ! login_types.iter().any(|login_type| login_type == web::MTX_LOGIN_SSO) {
+            return Err(MatrixError::Config(format!("server does not support {}",
+                                                   web::MTX_LOGIN_SSO)));
+        }
+        let listener = SsoListener::bind()?;
+        let url = web::sso_redirect_url(&self.api(), &listener.redirect_url()?);
+        self.prompt();
+        println!("please open this URL in your browser to log in:");
+        println!("{}", url);
+        let login_token = listener.wait_for_token(Duration::from_millis(sso::SSO_TIMEOUT))?;
+        let device = self.device();
+        let response = web::authenticate_token(&*self.transport, &self.api(), &login_token, &device)?;
+        self.adopt_user(&response.user_id);
+        self.save_device_id(response.device_id.as_deref());
+        self.clear_tokens();
+        self.save_tokens(&response.access_token, response.refresh_token.as_deref());
+        Ok(())
+    }
+
+    pub fn login_oidc(&mut self) -> Result<(), MatrixError> {
+        self.prompt();
+        println!("logging in with OIDC...");
+        let result = self.authenticate_oidc();
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("logged in as {}", self.user);
+            },
+            Err(e) => {
+                println!("authentication failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // the device authorization grant: the user approves on another device
+    fn authenticate_oidc(&mut self) -> Result<(), MatrixError> {
+        self.negotiate_api()?;
+        self.logged_in = false;
+        let metadata = oidc::get_auth_metadata(&*self.transport, &self.server)?;
+        debug!("auth metadata = {:?}", metadata);
+        let client_id = self.oidc_client_id(&metadata)?;
+        // with OIDC the client chooses the device_id
+        let device_id = self.get_option(DEVICE_ID_KEY).unwrap_or_else(oidc::gen_device_id);
+        let authorization = oidc::request_device_authorization(&*self.transport, &metadata,
+                                                               &client_id, &device_id)?;
+        self.prompt();
+        println!("please open {} and enter the code: {}",
+                 authorization.verification_uri, authorization.user_code);
+        if let Some(uri) = &authorization.verification_uri_complete {
+            self.prompt();
+            println!("or open {}", uri);
+        }
+        let tokens = oidc::poll_device_token(&*self.transport, &metadata, &client_id, &authorization)?;
+        let whoami = web::whoami(&*self.transport, &self.api(), &tokens.access_token)?;
+        self.adopt_user(&whoami.user_id);
+        self.save_device_id(Some(whoami.device_id.as_deref().unwrap_or(&device_id)));
+        self.clear_tokens();
+        // refresh at the issuer, not the homeserver
+        self.set(OIDC_TOKEN_ENDPOINT_KEY, &metadata.token_endpoint).unwrap();
+        self.save_tokens(&tokens.access_token, tokens.refresh_token.as_deref());
+        Ok(())
+    }
+
+    // register with the issuer once (and again if the issuer changes)
+    fn oidc_client_id(&mut self, metadata: &oidc::AuthMetadata) -> Result<String, MatrixError> {
+        let client_id = self.get_default(OIDC_CLIENT_ID_KEY, EMPTY);
+        if client_id.len() > 0 && self.get_default(OIDC_ISSUER_KEY, EMPTY) == metadata.issuer {
+            return Ok(client_id);
+        }
+        let client_id = oidc::register_client(&*self.transport, metadata)?;
+        debug!("registered with {} as client_id = {}", metadata.issuer, client_id);
+        self.set(OIDC_ISSUER_KEY, &metadata.issuer).unwrap();
+        self.set(OIDC_CLIENT_ID_KEY, &client_id).unwrap();
+        Ok(client_id)
+    }
+
+    pub fn register(&mut self) -> Result<(), MatrixError> {
+        self.prompt();
+        println!("registering...");
+        let result = self.create_account();
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("registered and logged in as {}", self.user);
+            },
+            Err(e) => {
+                println!("registration failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // register the user with the password (and whatever else the
+    // server asks for)
+    fn create_account(&mut self) -> Result<(), MatrixError> {
+        let user = self.get_default(USER_KEY, EMPTY);
+        let password = self.password.clone();
+        if user.len() == 0 || password.len() == 0 {
+            self.prompt();
+            println!("please /set user @USER:matrix.org");
+            self.prompt();
+            println!("please /password");
+            return Err(MatrixError::Config("user and password must be set".to_string()));
+        }
+        self.negotiate_api()?;
+        let username = web::get_username(&user);
+        let device = self.device();
+        let credentials = uia::Credentials { user, password: password.clone() };
+        let api = self.api();
+        let response = uia::authenticate(self, &credentials, |auth| {
+            web::register(&*self.transport, &api, &username, &password, &device, auth)
+        })?;
+        self.adopt_user(&response.user_id);
+        self.save_device_id(response.device_id.as_deref());
+        self.clear_tokens();
+        self.save_tokens(&response.access_token, response.refresh_token.as_deref());
+        self.forget_password();
+        Ok(())
+    }
+
+    // the account we logged in to decides who we are: keep the server
+    // (which we just logged in to), but the filter belongs to the old user
+    fn adopt_user(&mut self, user_id: &str) {
+        if user_id != self.user {
+            debug!("# user = '{}' (was '{}')", user_id, self.user);
+            self.user = user_id.to_string();
+            self.username = web::get_username(&self.user);
+            self.write_key(USER_KEY, &self.user.clone()).unwrap();
+            self.write_key(USERNAME_KEY, &self.username.clone()).unwrap();
+            self.unset(FILTER_KEY).unwrap();
+            self.filter = EMPTY.to_string();
+            self.rooms.clear();
+            self.unset(DEVICE_ID_KEY).unwrap();
+        }
+    }
+
+    // the device we log in as
+    fn device(&mut self) -> web::Device {
+        web::Device {
+            device_id: self.get_option(DEVICE_ID_KEY),
+            display_name: self.get_default(DEVICE_NAME_KEY, self.app),
+        }
+    }
+
+    // remember the device_id so that we log in as the same device next time
+    fn save_device_id(&mut self, device_id: Option<&str>) {
+        if let Some(device_id) = device_id {
+            if self.get_option(DEVICE_ID_KEY).as_deref() != Some(device_id) {
+                debug!("# device_id = {}", device_id);
+                self.set(DEVICE_ID_KEY, device_id).unwrap();
+            }
+        }
+    }
+
+    // renew the access token with the refresh token (if we have one)
+    fn refresh(&mut self) -> Result<(), MatrixError> {
+        let refresh_token = self.get_default(REFRESH_TOKEN_KEY, EMPTY);
+        if refresh_token.len() == 0 {
+            return Err(MatrixError::Config("no refresh token".to_string()));
+        }
+        let token_endpoint = self.get_default(OIDC_TOKEN_ENDPOINT_KEY, EMPTY);
+        let (access_token, new_refresh_token) = if token_endpoint.len() > 0 {
+            let client_id = self.get_default(OIDC_CLIENT_ID_KEY, EMPTY);
+            let tokens = oidc::refresh(&*self.transport, &token_endpoint, &client_id, &refresh_token)?;
+            (tokens.access_token, tokens.refresh_token)
+        } else {
+            self.negotiate_api()?;
+            let tokens = web::refresh(&*self.transport, &self.api(), &refresh_token)?;
+            (tokens.access_token, tokens.refresh_token)
+        };
+        debug!("access token refreshed");
+        self.save_tokens(&access_token, new_refresh_token.as_deref());
+        Ok(())
+    }
+
+    // store the new access token (and refresh token, if any)
+    fn save_tokens(&mut self, access_token: &str, refresh_token: Option<&str>) {
+        self.set(TOKEN_KEY, access_token).unwrap();
+        if let Some(refresh_token) = refresh_token {
+            self.set(REFRESH_TOKEN_KEY, refresh_token).unwrap();
+        }
+        self.token = access_token.to_string();
+        self.logged_in = true;
+    }
+
+    // forget the access (and refresh) token
+    fn clear_tokens(&mut self) {
+        self.unset(TOKEN_KEY).unwrap();
+        self.unset(REFRESH_TOKEN_KEY).unwrap();
+        self.unset(OIDC_TOKEN_ENDPOINT_KEY).unwrap();
+        self.token = EMPTY.to_string();
+    }
+
+    pub fn logout(&mut self, all: bool) -> Result<(), MatrixError> {
+        let result = self.end_session(all);
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                self.clear_tokens();
+                self.logged_in = false;
+                if all {
+                    println!("logged out of all devices");
+                } else {
+                    println!("logged out");
+                }
+            },
+            Err(e) => {
+                // keep the token so that the user may try again
+                println!("logout failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // invalidate the access token on the server
+    fn end_session(&mut self, all: bool) -> Result<(), MatrixError> {
+        let token = self.get_default(TOKEN_KEY, EMPTY);
+        if token.len() == 0 {
+            return Ok(());
+        }
+        let token_endpoint = self.get_default(OIDC_TOKEN_ENDPOINT_KEY, EMPTY);
+        if token_endpoint.len() > 0 && all {
+            // the issuer, not the homeserver, keeps the other sessions
+            let issuer = self.get_default(OIDC_ISSUER_KEY, EMPTY);
+            return Err(MatrixError::Config(format!(
+                "cannot log out all devices of an OIDC login: sign them out at {}", issuer)));
+        }
+        self.negotiate_api()?;
+        let result = if token_endpoint.len() > 0 {
+            // revoking the refresh token ends the OIDC session
+            let metadata = oidc::get_auth_metadata(&*self.transport, &self.server)?;
+            let client_id = self.get_default(OIDC_CLIENT_ID_KEY, EMPTY);
+            let refresh_token = self.get_default(REFRESH_TOKEN_KEY, EMPTY);
+            if refresh_token.len() > 0 {
+                oidc::revoke(&*self.transport, &metadata, &client_id, &refresh_token, "refresh_token")
+            } else {
+                oidc::revoke(&*self.transport, &metadata, &client_id, &token, "access_token")
+            }
+        } else {
+            web::logout(&*self.transport, &self.api(), &token, all)
+        };
+        match result {
+            Err(e) if e.is_logged_out() => {
+                debug!("token was already invalid: {}", e);
+                Ok(())
+            },
+            result => result
+        }
+    }
+
+    pub fn change_password(&mut self, logout_devices: bool) -> Result<(), MatrixError> {
+        let result = self.update_password(logout_devices);
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                if logout_devices {
+                    println!("password changed and other devices logged out");
                 } else {
-                    if let Some(new_token) = web::authenticate_user(&self.server, &user, &password) {
-                        self.set(TOKEN_KEY, &new_token).unwrap();
-                        self.token = new_token;
-                        self.logged_in = true;
+                    println!("password changed");
+                }
+            },
+            Err(e) => {
+                println!("password change failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // change the password on the server (keeping this session)
+    fn update_password(&mut self, logout_devices: bool) -> Result<(), MatrixError> {
+        self.connect()?;
+        let new_password = self.ask_secret("new password:").unwrap_or_default();
+        if new_password.len() == 0 {
+            return Err(MatrixError::Config("the new password is empty".to_string()));
+        }
+        if self.ask_secret("new password again:").as_deref() != Some(new_password.as_str()) {
+            return Err(MatrixError::Config("the passwords do not match".to_string()));
+        }
+        let credentials = self.credentials();
+        self.with_session(|mtxcli| {
+            uia::authenticate(&*mtxcli, &credentials, |auth| {
+                web::change_password(&*mtxcli.transport, &mtxcli.api(), &mtxcli.token,
+                                     &new_password, logout_devices, auth)
+            })
+        })?;
+        // keep a known password up to date (without clearing our token)
+        if self.password.len() > 0 {
+            self.store_password(&new_password);
+        }
+        Ok(())
+    }
+
+    pub fn deactivate(&mut self, erase: bool) -> Result<(), MatrixError> {
+        let user = self.user.clone();
+        let result = self.deactivate_account(erase);
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("deactivated {}", user);
+            },
+            Err(e) => {
+                println!("deactivation failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // deactivate the account for good (once the user has confirmed)
+    fn deactivate_account(&mut self, erase: bool) -> Result<(), MatrixError> {
+        self.connect()?;
+        let user = self.user.clone();
+        self.prompt();
+        if erase {
+            println!("{} will be deactivated and its messages erased: this cannot be undone!", user);
+        } else {
+            println!("{} will be deactivated: this cannot be undone!", user);
+        }
+        if self.ask(&format!("type {} to confirm:", user)).as_deref() != Some(user.as_str()) {
+            return Err(MatrixError::Config("not confirmed".to_string()));
+        }
+        let credentials = self.credentials();
+        self.with_session(|mtxcli| {
+            uia::authenticate(&*mtxcli, &credentials, |auth| {
+                web::deactivate(&*mtxcli.transport, &mtxcli.api(), &mtxcli.token, erase, auth)
+            })
+        })?;
+        // the server has logged out all the devices of the account
+        self.clear_tokens();
+        self.logged_in = false;
+        self.store_password(EMPTY);
+        self.unset(DEVICE_ID_KEY).unwrap();
+        self.unset(FILTER_KEY).unwrap();
+        self.filter = EMPTY.to_string();
+        self.rooms.clear();
+        Ok(())
+    }
+
+    pub fn list_devices(&mut self) -> Result<(), MatrixError> {
+        let result = self.get_devices();
+        self.prompt();
+        match &result {
+            Ok(devices) => {
+                let current = self.get_option(DEVICE_ID_KEY);
+                for device in devices.iter() {
+                    let marker = if current.as_deref() == Some(device.device_id.as_str()) {
+                        "*"
                     } else {
-                        error!("Error: may NOT login with type: {}", web::MTX_LOGIN_PASSWORD);
-                    }
+                        " "
+                    };
+                    let name = device.display_name.as_deref().unwrap_or(EMPTY);
+                    let last_seen = match device.last_seen_ts {
+                        Some(ts) => web::format_timestamp(ts),
+                        None => "never".to_string(),
+                    };
+                    let ip = device.last_seen_ip.as_deref().unwrap_or("-");
+                    println!("{} {:<12} {:<20} {} {}", marker, device.device_id, name, last_seen, ip);
                 }
+            },
+            Err(e) => {
+                println!("could not list devices: {}", e);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    // the devices of the user (sorted by last seen, most recent first)
+    fn get_devices(&mut self) -> Result<Vec<web::DeviceInfo>, MatrixError> {
+        self.connect()?;
+        let mut devices = self.with_session(|mtxcli| {
+            web::get_devices(&*mtxcli.transport, &mtxcli.api(), &mtxcli.token)
+        })?;
+        devices.sort_by_key(|device| std::cmp::Reverse(device.last_seen_ts));
+        Ok(devices)
+    }
+
+    pub fn rename_device(&mut self, device_id: &str, display_name: &str) -> Result<(), MatrixError> {
+        let result = self.connect().and_then(|()| {
+            self.with_session(|mtxcli| {
+                web::rename_device(&*mtxcli.transport, &mtxcli.api(), &mtxcli.token,
+                                   device_id, display_name)
+            })
+        });
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("renamed {} to {}", device_id, display_name);
+            },
+            Err(e) => {
+                println!("rename failed: {}", e);
             }
         }
+        result
+    }
+
+    pub fn delete_devices(&mut self, device_ids: &[String]) -> Result<(), MatrixError> {
+        let result = self.remove_devices(device_ids);
         self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("deleted {}", device_ids.join(" "));
+            },
+            Err(e) => {
+                println!("delete failed: {}", e);
+            }
+        }
+        result
+    }
+
+    // delete the devices (with user-interactive authentication)
+    fn remove_devices(&mut self, device_ids: &[String]) -> Result<(), MatrixError> {
+        self.connect()?;
+        let credentials = self.credentials();
+        self.with_session(|mtxcli| {
+            uia::authenticate(&*mtxcli, &credentials, |auth| {
+                web::delete_devices(&*mtxcli.transport, &mtxcli.api(), &mtxcli.token,
+                                    device_ids, auth)
+            })
+        })?;
+        let current = self.get_option(DEVICE_ID_KEY);
+        if device_ids.iter().any(|device_id| Some(device_id) == current.as_ref()) {
+            // we deleted ourselves: the token is gone with the device
+            self.clear_tokens();
+            self.logged_in = false;
+            self.unset(DEVICE_ID_KEY).unwrap();
+        }
+        Ok(())
+    }
+
+    // login unless we already are
+    fn connect(&mut self) -> Result<(), MatrixError> {
         if self.logged_in {
-            println!("logged in");
+            Ok(())
         } else {
-            println!("authentication failed");
+            self.login()
         }
-        self.logged_in
     }
 
-    pub fn logout(&mut self) {
-        self.unset(TOKEN_KEY).unwrap();
-        self.prompt();
-        println!("logged out");
-        self.logged_in = false;
+    // who we are for user-interactive authentication
+    fn credentials(&mut self) -> uia::Credentials {
+        uia::Credentials {
+            user: self.user.clone(),
+            password: self.password.clone(),
+        }
     }
 
     // assume logged in, token is valid
-    pub fn get_room_id(&mut self) -> bool {
+    pub fn get_room_id(&mut self) -> Result<(), MatrixError> {
         if self.room_id.len() > 0 {
-            true
-        } else {
-            let room = self.get_default(ROOM_KEY, EMPTY);
-            let server = self.get_default(SERVER_KEY, EMPTY);
-            if room.len() == 0 {
-                self.prompt();
-                println!("please /set room my-room-to-join");
-                false
-            } else if server.len() == 0 {
-                self.prompt();
-                println!("please /set server my-matrix-server");
-                false
-            } else {
-                let mut room_server = String::new();
-                if ! room.starts_with("#") {
-                    room_server.push_str("#");
+            return Ok(());
+        }
+        let room = self.get_default(ROOM_KEY, EMPTY);
+        if room.len() == 0 {
+            return Err(MatrixError::Config("please /room my-room-to-join".to_string()));
+        }
+        let new_room_id = self.resolve_room(&room)?;
+        if self.rooms.is_loaded() && self.rooms.get(&new_room_id).is_none() {
+            // else the sync would just show nothing
+            return Err(MatrixError::Config(format!("you have not joined {}: please /join {}", room, room)));
+        }
+        self.set(ROOM_ID_KEY, &new_room_id).unwrap();
+        self.room_id = new_room_id;
+        Ok(())
+    }
+
+    // the room_id of a joined room (by index, room_id or alias) else
+    // of the room alias (looked up in the room directory)
+    fn resolve_room(&mut self, room: &str) -> Result<String, MatrixError> {
+        let server_name = web::get_server_name(&self.user);
+        if let Some(joined) = self.rooms.find(room, &server_name) {
+            return Ok(joined.room_id.clone());
+        }
+        if room.starts_with('!') {
+            return Ok(room.to_string());
+        }
+        let room_alias = self.room_alias(room)?;
+        web::get_room_id(&*self.transport, &self.api(), &room_alias, &self.token)
+    }
+
+    // the full room alias: #room:server_name
+    fn room_alias(&mut self, room: &str) -> Result<String, MatrixError> {
+        let mut room_server = String::new();
+        if ! room.starts_with("#") {
+            room_server.push_str("#");
+        }
+        room_server.push_str(room);
+        if ! room.contains(":") {
+            // the room alias belongs to the user's server name, which may
+            // differ from the (delegated) homeserver
+            let mut server_name = web::get_server_name(&self.user);
+            if server_name.len() == 0 {
+                let server = self.get_default(SERVER_KEY, EMPTY);
+                if server.len() == 0 {
+                    return Err(MatrixError::Config("please /set server my-matrix-server".to_string()));
                 }
-                room_server.push_str(&room);
-                room_server.push_str(":");
                 let i = match server.find(HTTPS) {
                     Some(index) => {
                         index + HTTPS.len()
@@ -358,52 +1289,383 @@ impl Mtxcli {
                     },
                 };
                 if i >= server.len() {
-                    self.prompt();
-                    println!("please /set server my-matrix-server (INVALID)");
-                    false
-                } else {
-                    room_server.push_str(&server[i..]);
-                    if let Some(new_room_id) = web::get_room_id(&self.server, &room_server, &self.token) {
-                        self.set(ROOM_ID_KEY, &new_room_id).unwrap();
-                        self.room_id = new_room_id;
-                        true
-                    } else {
-                        false
-                    }
+                    return Err(MatrixError::Config("please /set server my-matrix-server (INVALID)".to_string()));
                 }
+                server_name = (&server[i..]).to_string();
             }
+            room_server.push_str(":");
+            room_server.push_str(&server_name);
         }
+        Ok(room_server)
     }
 
-    // assume logged in, token is valid, room_id is valid, user is valid
-    pub fn get_filter(&mut self) -> bool {
-        if self.filter.len() > 0 {
-            true
+    /// Show the room chat lines are sent to, or switch to another
+    /// joined room (by index, room_id or alias)
+    pub fn switch_room(&mut self, room: &str) -> Result<(), MatrixError> {
+        let result = self.change_room(room);
+        self.prompt();
+        match &result {
+            Ok(()) if self.room_id.len() > 0 => {
+                let room_id = self.room_id.clone();
+                println!("talking in {}", self.room_label(&room_id));
+            },
+            Ok(()) => {
+                println!("not in a room: please /room my-room-to-join");
+            },
+            Err(e) => {
+                println!("could not switch room: {}", e);
+            }
+        }
+        result
+    }
+
+    // assume nothing: the filter and since are kept as they are
+    fn change_room(&mut self, room: &str) -> Result<(), MatrixError> {
+        self.connect()?;
+        self.with_session(Self::load_rooms)?;
+        if room.len() == 0 {
+            if self.get_option(ROOM_KEY).is_some() {
+                self.with_session(Self::get_room_id)?;
+            }
+            return Ok(());
+        }
+        let room_id = self.with_session(|mtxcli| mtxcli.resolve_room(room))?;
+        if self.rooms.get(&room_id).is_none() {
+            return Err(MatrixError::Config(format!("you have not joined {}: please /join {}", room, room)));
+        }
+        // remember the alias rather than the index the user typed
+        self.set_active_room(&room_id, &room_id);
+        Ok(())
+    }
+
+    /// List the joined rooms (after a sync, for fresh unread counts)
+    pub fn list_rooms(&mut self) {
+        self.user_says(EMPTY);
+        if ! self.rooms.is_loaded() {
+            return; // the error has been shown
+        }
+        self.load_rooms_state();
+        self.prompt();
+        if self.rooms.iter().next().is_none() {
+            println!("no rooms: please /join my-room-to-join");
+            return;
+        }
+        println!("rooms:");
+        for (i, room) in self.rooms.iter().enumerate() {
+            let marker = if room.room_id == self.room_id { "*" } else { " " };
+            let mut line = format!("{:>3} {} {}", i + 1, marker, room.display_name());
+            if let (Some(_), Some(alias)) = (&room.name, &room.canonical_alias) {
+                line.push_str(&format!(" ({})", alias));
+            }
+            if room.encrypted {
+                line.push_str(" [encrypted]");
+            }
+            if room.notification_count > 0 || room.highlight_count > 0 {
+                line.push_str(&format!(" {} unread", room.notification_count));
+                if room.highlight_count > 0 {
+                    line.push_str(&format!(", {} highlighted", room.highlight_count));
+                }
+            }
+            println!("{}", line);
+        }
+    }
+
+    // chat lines are now sent to the room, remembered by its canonical
+    // alias (else by name)
+    fn set_active_room(&mut self, room_id: &str, name: &str) {
+        let alias = self.rooms.get(room_id)
+            .and_then(|room| room.canonical_alias.clone())
+            .unwrap_or_else(|| name.to_string());
+        self.set(ROOM_KEY, &alias).unwrap();
+        self.set(ROOM_ID_KEY, room_id).unwrap();
+        self.room_id = room_id.to_string();
+        debug!("room = {} ({})", alias, self.room_id);
+    }
+
+    // talk in a room we just joined or created: it is numbered last
+    // and labelled by name once the next sync brings its state
+    fn enter_room(&mut self, room_id: &str, name: &str) {
+        self.rooms.add(room_id);
+        self.set_active_room(room_id, name);
+    }
+
+    // there is no room to send chat lines to
+    fn clear_active_room(&mut self) {
+        self.unset(ROOM_KEY).unwrap();
+        self.unset(ROOM_ID_KEY).unwrap();
+        self.room_id = EMPTY.to_string();
+    }
+
+    pub fn join(&mut self, room: &str, via: &[String]) -> Result<(), MatrixError> {
+        let result = self.join_room(room, via);
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("joined {}", room);
+                // show what was said there
+                self.user_says(EMPTY);
+            },
+            Err(e) => {
+                println!("could not join {}: {}", room, e);
+            }
+        }
+        result
+    }
+
+    // join the room (by room_id or alias) and talk in it
+    fn join_room(&mut self, room: &str, via: &[String]) -> Result<(), MatrixError> {
+        self.connect()?;
+        self.with_session(Self::load_rooms)?;
+        let room_id_or_alias = if room.starts_with('!') {
+            room.to_string()
         } else {
-            if let Some(new_filter) = web::get_filter(&self.user, &self.server,
-                                                      &self.room_id, &self.token) {
-                self.set(FILTER_KEY, &new_filter).unwrap();
-                self.filter = new_filter;
-                true
-            } else {
-                false
+            self.room_alias(room)?
+        };
+        let room_id = self.with_session(|mtxcli| {
+            web::join_room(&*mtxcli.transport, &mtxcli.api(), &room_id_or_alias, via, &mtxcli.token)
+        })?;
+        self.enter_room(&room_id, &room_id_or_alias);
+        Ok(())
+    }
+
+    pub fn leave(&mut self, args: &str) -> Result<(), MatrixError> {
+        let result = self.leave_room(args);
+        self.prompt();
+        match &result {
+            Ok(label) => {
+                println!("left {}", label);
+            },
+            Err(e) => {
+                println!("could not leave: {}", e);
             }
         }
+        result.map(|_| ())
+    }
+
+    // leave the room named by the first word of args (else the room we
+    // are talking in), the rest is the reason; returns the room label
+    fn leave_room(&mut self, args: &str) -> Result<String, MatrixError> {
+        self.connect()?;
+        self.with_session(Self::load_rooms)?;
+        let server_name = web::get_server_name(&self.user);
+        let (first, rest) = args.split_once(' ').unwrap_or((args, EMPTY));
+        let is_room = first.starts_with('#') || first.starts_with('!')
+            || self.rooms.find(first, &server_name).is_some()
+            || (rooms::may_be_alias(first) && {
+                // it may be the alias of a room we cannot label yet
+                self.load_rooms_state();
+                self.rooms.find(first, &server_name).is_some()
+            });
+        let (room_id, reason) = if is_room {
+            (self.with_session(|mtxcli| mtxcli.resolve_room(first))?, rest.trim())
+        } else {
+            self.with_session(Self::get_room_id)?;
+            (self.room_id.clone(), args)
+        };
+        let label = self.room_label(&room_id);
+        let reason = Some(reason).filter(|reason| reason.len() > 0);
+        self.with_session(|mtxcli| {
+            web::leave_room(&*mtxcli.transport, &mtxcli.api(), &room_id, reason, &mtxcli.token)
+        })?;
+        self.rooms.remove(&room_id);
+        if room_id == self.room_id {
+            self.clear_active_room();
+        }
+        Ok(label)
+    }
+
+    pub fn forget(&mut self, room: &str) -> Result<(), MatrixError> {
+        let result = self.forget_room(room);
+        self.prompt();
+        match &result {
+            Ok(()) => {
+                println!("forgot {}", room);
+            },
+            Err(e) => {
+                println!("could not forget {}: {}", room, e);
+            }
+        }
+        result
+    }
+
+    // forget a room we have left
+    fn forget_room(&mut self, room: &str) -> Result<(), MatrixError> {
+        self.connect()?;
+        let room_id = self.with_session(|mtxcli| mtxcli.resolve_room(room))?;
+        self.with_session(|mtxcli| {
+            web::forget_room(&*mtxcli.transport, &mtxcli.api(), &room_id, &mtxcli.token)
+        })
+    }
+
+    pub fn create(&mut self, options: &RoomOptions) -> Result<(), MatrixError> {
+        let result = self.create_room(options);
+        self.prompt();
+        match &result {
+            Ok(room) => {
+                println!("created {}", room);
+                if options.is_encrypted() {
+                    println!("note: mtxcli cannot read or send encrypted messages in {}", room);
+                }
+                // show the room as the server set it up
+                self.user_says(EMPTY);
+            },
+            Err(e) => {
+                println!("could not create room: {}", e);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    // create the room and talk in it, returns its alias (else name)
+    fn create_room(&mut self, options: &RoomOptions) -> Result<String, MatrixError> {
+        self.connect()?;
+        self.with_session(Self::load_rooms)?;
+        let room_alias = match &options.room_alias_name {
+            Some(alias) => Some(self.room_alias(alias)?),
+            None => None,
+        };
+        let room_id = self.with_session(|mtxcli| {
+            web::create_room(&*mtxcli.transport, &mtxcli.api(), options, &mtxcli.token)
+        })?;
+        let room = room_alias.or_else(|| options.name.clone())
+            .unwrap_or_else(|| room_id.clone());
+        self.enter_room(&room_id, &room);
+        Ok(room)
+    }
+
+    // assume logged in, token is valid: the state of the rooms comes
+    // with sync (or from room_label)
+    pub fn load_rooms(&mut self) -> Result<(), MatrixError> {
+        if self.rooms.is_loaded() {
+            return Ok(());
+        }
+        let room_ids = web::get_joined_room_ids(&*self.transport, &self.api(), &self.token)?;
+        self.rooms.load(&room_ids);
+        debug!("rooms = {:?}", self.rooms);
+        Ok(())
+    }
+
+    // ask for the state of a joined room sync has not told us about
+    // (e.g. a quiet room when starting again with a saved since)
+    fn load_room_state(&mut self, room_id: &str) {
+        if ! self.rooms.needs_state(room_id) {
+            return;
+        }
+        match web::get_room_state(&*self.transport, &self.api(), room_id, &self.user, &self.token) {
+            Ok(update) => {
+                self.rooms.update_room(&update);
+            },
+            Err(e) => {
+                debug!("could not get the state of {}: {}", room_id, e);
+            }
+        }
+    }
+
+    // e.g. to list them all
+    fn load_rooms_state(&mut self) {
+        let room_ids: Vec<String> = self.rooms.iter().map(|room| room.room_id.clone()).collect();
+        for room_id in room_ids.iter() {
+            self.load_room_state(room_id);
+        }
+    }
+
+    // how the room is shown (its room_id if we cannot tell)
+    fn room_label(&mut self, room_id: &str) -> String {
+        self.load_room_state(room_id);
+        let server_name = web::get_server_name(&self.user);
+        self.rooms.label(room_id, &server_name)
+    }
+
+    // assume logged in, token is valid, user is valid
+    pub fn get_filter(&mut self) -> Result<(), MatrixError> {
+        if self.filter.len() > 0 {
+            return Ok(());
+        }
+        let new_filter = web::get_filter(&*self.transport, &self.api(), &self.user,
+                                         &self.token)?;
+        self.set(FILTER_KEY, &new_filter).unwrap();
+        self.filter = new_filter;
+        Ok(())
     }
 
-    // assume logged in, token is valid, room_id is valid, user is valid,
-    // and filter is valid
-    pub fn read_messages(&mut self) {
-        if let Some((since, messages)) = web::client_sync(&self.server, &self.filter,
-                                                          &self.since, MTX_TIMEOUT,
-                                                          &self.room_id, &self.token) {
-            self.set(SINCE_KEY, &since).unwrap();
-            self.since = since;
-            debug!("since = {}", self.since);
-            if messages.len() > 0 {
-                print!("{}", messages);
+    // assume logged in, token is valid, room_id is valid
+    pub fn send_message(&mut self, text: &str) -> Result<(), MatrixError> {
+        web::send_message(&*self.transport, &self.api(), &self.room_id, text, &self.token)
+    }
+
+    // assume logged in, token is valid, user is valid, filter is valid
+    // and the rooms are loaded
+    pub fn read_messages(&mut self) -> Result<(), MatrixError> {
+        let sync = web::client_sync(&*self.transport, &self.api(), &self.filter,
+                                    &self.since, self.http_config.sync_timeout,
+                                    &self.token)?;
+        self.set(SINCE_KEY, &sync.next_batch).unwrap();
+        self.since = sync.next_batch.clone();
+        debug!("since = {}", self.since);
+        let mut messages = String::new();
+        // e.g. kicked, or left with another client
+        for room_id in sync.left.iter() {
+            if self.rooms.get(room_id).is_some() {
+                messages.push_str(&format!("{} you are no longer in this room\n",
+                                           self.room_label(room_id)));
+                if *room_id == self.room_id {
+                    self.clear_active_room();
+                }
             }
         }
+        self.rooms.update(&sync);
+        for room in sync.joined.iter().filter(|room| room.messages.len() > 0) {
+            let label = self.room_label(&room.room_id);
+            for message in room.messages.iter() {
+                messages.push_str(&format!("{} {}> {}\n", label, web::get_username(&message.sender), message.body));
+            }
+        }
+        if messages.len() > 0 {
+            print!("{}", messages);
+        }
+        Ok(())
+    }
+}
+
+/// Reads a line from the terminal without echo
+#[cfg(any(unix, windows))]
+fn read_password() -> Option<String> {
+    rpassword::read_password().ok()
+}
+
+/// Reads a line from stdin (there is no way to turn off the echo)
+#[cfg(not(any(unix, windows)))]
+fn read_password() -> Option<String> {
+    read_secret_line()
+}
+
+/// Reads a secret line from stdin: the line ending is removed in place
+/// so that no copy of the secret is left behind
+fn read_secret_line() -> Option<String> {
+    let mut answer = Zeroizing::new(String::new());
+    match io::stdin().read_line(&mut answer) {
+        Ok(n) if n > 0 => {
+            let length = answer.trim_end_matches(&['\n', '\r'][..]).len();
+            answer.truncate(length);
+            Some(mem::take(&mut *answer))
+        },
+        _ => None,
+    }
+}
+
+/// The user answers the stages of user-interactive authentication
+impl uia::Prompt for Mtxcli {
+    fn tell(&self, text: &str) {
+        self.prompt();
+        println!("{}", text);
+    }
+
+    fn ask(&self, question: &str) -> Option<String> {
+        Mtxcli::ask(self, question)
+    }
+
+    fn ask_secret(&self, question: &str) -> Option<String> {
+        Mtxcli::ask_secret(self, question)
     }
 }
 
@@ -425,3 +1687,257 @@ impl fmt::Display for Mtxcli {
         write!(f, "{}{}{}", a, self.system, b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use ureq::serde_json::{json, Value};
+
+    use super::*;
+    use crate::mtxcli::transport::{Method, Request};
+    use crate::mtxcli::transport::scripted::ScriptedTransport;
+
+    const ALICE: &str = "@alice:localhost";
+
+    // a Mtxcli with a fresh configuration directory which talks to the
+    // scripted transport (and never asks for a passphrase), also used
+    // by the tests of the submodules
+    pub(crate) fn mtxcli(name: &str, transport: &ScriptedTransport) -> Mtxcli {
+        let config_dir = env::temp_dir().join(format!("mtxcli-test-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&config_dir).ok();
+        let config_dir = config_dir.to_str().unwrap().to_string();
+        let mut mtxcli = Mtxcli::with_transport("io", "Betrusted", "mtxcli", "0.0.0",
+                                                &config_dir, Box::new(transport.clone()));
+        mtxcli.secrets.decline();
+        mtxcli.write_key(USER_KEY, ALICE).unwrap();
+        mtxcli.user = ALICE.to_string();
+        mtxcli.username = "alice".to_string();
+        mtxcli.server = "http://localhost:8008".to_string();
+        mtxcli.password = "secret".to_string();
+        mtxcli
+    }
+
+    fn script_login(transport: &ScriptedTransport) {
+        transport
+            .respond(Method::Get, "/_matrix/client/versions", Ok(json!({ "versions": [ "v1.1" ] })))
+            .respond(Method::Get, "/v3/login", Ok(json!({ "flows": [ { "type": "m.login.password" } ] })))
+            .respond(Method::Post, "/v3/login", Ok(json!({
+                "user_id": ALICE,
+                "access_token": "token1",
+                "device_id": "DEVICE1",
+            })));
+    }
+
+    fn body(request: &Request) -> Value {
+        ureq::serde_json::from_str(request.body.as_deref().unwrap_or("null")).unwrap()
+    }
+
+    pub(crate) fn cleanup(mtxcli: &Mtxcli) {
+        fs::remove_dir_all(&mtxcli.system.config_dir).ok();
+    }
+
+    #[test]
+    fn login_with_password_and_create_filter() {
+        let transport = ScriptedTransport::new();
+        script_login(&transport);
+        transport.respond(Method::Post, "/user/%40alice%3Alocalhost/filter", Ok(json!({ "filter_id": "7" })));
+        let mut mtxcli = mtxcli("login", &transport);
+        mtxcli.login().unwrap();
+        assert!(mtxcli.logged_in);
+        assert_eq!(mtxcli.token, "token1");
+        assert_eq!(mtxcli.api_prefix, web::CLIENT_V3);
+        assert_eq!(mtxcli.get_option(DEVICE_ID_KEY).as_deref(), Some("DEVICE1"));
+        // the password is only kept if remember_password
+        assert_eq!(mtxcli.password, EMPTY);
+        mtxcli.get_filter().unwrap();
+        assert_eq!(mtxcli.filter, "7");
+        assert_eq!(mtxcli.get_option(FILTER_KEY).as_deref(), Some("7"));
+        let requests = transport.requests();
+        let login = body(&requests[2]);
+        assert_eq!(login["identifier"]["user"], ALICE);
+        assert_eq!(login["password"], "secret");
+        let filter = body(&requests[3]);
+        assert_eq!(filter["room"]["timeline"]["types"][0], "m.room.message");
+        assert_eq!(requests[3].token.as_deref(), Some("token1"));
+        assert_eq!(transport.remaining(), 0);
+        cleanup(&mtxcli);
+    }
+
+    #[test]
+    fn resolve_rooms_by_index_room_id_and_alias() {
+        let transport = ScriptedTransport::new();
+        script_login(&transport);
+        transport
+            .respond(Method::Get, "/joined_rooms", Ok(json!({ "joined_rooms": [ "!a:localhost", "!b:localhost" ] })))
+            .respond(Method::Get, "/directory/room/%23test%3Alocalhost", Ok(json!({ "room_id": "!b:localhost" })))
+            .respond(Method::Get, "/directory/room/%23other%3Alocalhost", Ok(json!({ "room_id": "!c:localhost" })))
+            .respond(Method::Get, "/directory/room/%23nope%3Alocalhost",
+                     Err(MatrixError::from_status(404, r#"{"errcode":"M_NOT_FOUND","error":"Room alias not found"}"#)));
+        let mut mtxcli = mtxcli("resolve", &transport);
+        mtxcli.login().unwrap();
+        mtxcli.load_rooms().unwrap();
+        assert_eq!(mtxcli.resolve_room("2").unwrap(), "!b:localhost");
+        assert_eq!(mtxcli.resolve_room("!a:localhost").unwrap(), "!a:localhost");
+        // an alias we cannot label yet is looked up in the directory
+        mtxcli.write_key(ROOM_KEY, "test").unwrap();
+        mtxcli.get_room_id().unwrap();
+        assert_eq!(mtxcli.room_id, "!b:localhost");
+        assert_eq!(mtxcli.get_option(ROOM_ID_KEY).as_deref(), Some("!b:localhost"));
+        // rooms we have not joined cannot be talked in
+        mtxcli.room_id = EMPTY.to_string();
+        mtxcli.write_key(ROOM_KEY, "#other:localhost").unwrap();
+        let e = mtxcli.get_room_id().unwrap_err();
+        assert!(e.to_string().contains("please /join #other:localhost"), "{}", e);
+        assert!(mtxcli.resolve_room("nope").is_err());
+        assert_eq!(transport.remaining(), 0);
+        cleanup(&mtxcli);
+    }
+
+    #[test]
+    fn leave_with_a_reason() {
+        let transport = ScriptedTransport::new();
+        script_login(&transport);
+        let not_found = || Err(MatrixError::from_status(404, r#"{"errcode":"M_NOT_FOUND","error":"Event not found"}"#));
+        transport
+            .respond(Method::Get, "/joined_rooms", Ok(json!({ "joined_rooms": [ "!a:localhost", "!b:localhost" ] })))
+            // the label of the room left (not of the others)
+            .respond(Method::Get, "/state/m.room.name/", Ok(json!({ "name": "Alpha" })))
+            .respond(Method::Get, "/state/m.room.canonical_alias/", not_found())
+            .respond(Method::Get, "/state/m.room.encryption/", not_found())
+            .respond(Method::Post, "/leave", Ok(json!({})));
+        let mut mtxcli = mtxcli("leave", &transport);
+        mtxcli.login().unwrap();
+        mtxcli.write_key(ROOM_KEY, "1").unwrap();
+        // "Bye" cannot be an alias: the reason is for the current room
+        assert_eq!(mtxcli.leave_room("Bye for now").unwrap(), "Alpha");
+        let requests = transport.requests();
+        let leave = requests.last().unwrap();
+        assert!(leave.url.contains("/rooms/%21a%3Alocalhost/leave"), "{}", leave.url);
+        assert_eq!(leave.body.as_deref(), Some(r#"{"reason":"Bye for now"}"#));
+        assert!(requests.iter().all(|request| ! request.url.contains("%21b%3Alocalhost")));
+        assert_eq!(transport.remaining(), 0);
+        cleanup(&mtxcli);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        let transport = ScriptedTransport::new();
+        script_login(&transport);
+        transport
+            .respond(Method::Post, "/filter", Ok(json!({ "filter_id": "7" })))
+            .respond(Method::Get, "/joined_rooms", Ok(json!({ "joined_rooms": [ "!a:localhost" ] })))
+            .respond(Method::Get, "/sync", Ok(json!({
+                "next_batch": "s1",
+                "rooms": { "join": { "!a:localhost": {
+                    "state": { "events": [
+                        { "type": "m.room.canonical_alias", "state_key": "", "content": { "alias": "#test:localhost" } },
+                    ] },
+                    "timeline": { "events": [
+                        { "type": "m.room.message", "sender": "@bob:localhost", "content": { "body": "hi alice" } },
+                    ] },
+                    "unread_notifications": { "notification_count": 1, "highlight_count": 1 },
+                } } },
+            })))
+            .respond(Method::Put, "/rooms/%21a%3Alocalhost/send/m.room.message/", Ok(json!({ "event_id": "$1" })))
+            .respond(Method::Get, "/sync", Ok(json!({
+                "next_batch": "s2",
+                "rooms": { "join": { "!a:localhost": {
+                    "timeline": { "events": [
+                        { "type": "m.room.message", "sender": ALICE, "content": { "body": "hello bob" } },
+                    ] },
+                    "unread_notifications": { "notification_count": 0, "highlight_count": 0 },
+                } } },
+            })));
+        let mut mtxcli = mtxcli("send", &transport);
+        mtxcli.write_key(ROOM_KEY, "test").unwrap();
+        mtxcli.user_says("hello bob");
+        assert_eq!(transport.remaining(), 0);
+        assert_eq!(mtxcli.room_id, "!a:localhost");
+        assert_eq!(mtxcli.since, "s2");
+        assert_eq!(mtxcli.get_option(SINCE_KEY).as_deref(), Some("s2"));
+        let room = mtxcli.rooms.get("!a:localhost").unwrap();
+        assert_eq!(room.label("localhost"), "#test");
+        assert_eq!(room.notification_count, 0);
+        let requests = transport.requests();
+        let send = requests.iter().find(|request| request.method == Method::Put).unwrap();
+        assert_eq!(body(send)["body"], "hello bob");
+        assert!(send.idempotent);
+        let syncs: Vec<&Request> = requests.iter().filter(|request| request.url.contains("/sync")).collect();
+        assert!(syncs[0].url.contains("filter=7") && ! syncs[0].url.contains("since="));
+        assert!(syncs[1].url.contains("since=s1"));
+        assert!(syncs.iter().all(|request| request.long_poll));
+        cleanup(&mtxcli);
+    }
+
+    #[test]
+    fn logout_revokes_the_oidc_session() {
+        let transport = ScriptedTransport::new();
+        transport
+            .respond(Method::Get, "/_matrix/client/versions", Ok(json!({ "versions": [ "v1.1" ] })))
+            .respond(Method::Get, "/auth_metadata", Ok(json!({
+                "issuer": "http://localhost/",
+                "token_endpoint": "http://localhost/oauth2/token",
+                "revocation_endpoint": "http://localhost/oauth2/revoke",
+            })))
+            .respond(Method::Post, "/oauth2/revoke", Ok(json!({})));
+        let mut mtxcli = mtxcli("oidc-logout", &transport);
+        mtxcli.set(OIDC_TOKEN_ENDPOINT_KEY, "http://localhost/oauth2/token").unwrap();
+        mtxcli.set(OIDC_CLIENT_ID_KEY, "client1").unwrap();
+        mtxcli.save_tokens("mat_access", Some("mar_refresh"));
+        mtxcli.logout(false).unwrap();
+        assert!(! mtxcli.logged_in);
+        assert_eq!(mtxcli.get_option(TOKEN_KEY), None);
+        assert_eq!(mtxcli.get_option(REFRESH_TOKEN_KEY), None);
+        assert_eq!(mtxcli.get_option(OIDC_TOKEN_ENDPOINT_KEY), None);
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        // the issuer, not the homeserver, ends the session
+        assert_eq!(requests[2].body.as_deref(),
+                   Some("token=mar_refresh&token_type_hint=refresh_token&client_id=client1"));
+        cleanup(&mtxcli);
+    }
+
+    #[test]
+    fn logout_all_is_up_to_the_issuer() {
+        let transport = ScriptedTransport::new();
+        let mut mtxcli = mtxcli("oidc-logout-all", &transport);
+        mtxcli.set(OIDC_TOKEN_ENDPOINT_KEY, "http://localhost/oauth2/token").unwrap();
+        mtxcli.set(OIDC_ISSUER_KEY, "http://localhost/").unwrap();
+        mtxcli.save_tokens("mat_access", Some("mar_refresh"));
+        let e = mtxcli.logout(true).unwrap_err();
+        assert_eq!(e.to_string(), "cannot log out all devices of an OIDC login: sign them out at http://localhost/");
+        // still logged in, and nothing was sent
+        assert!(mtxcli.logged_in);
+        assert_eq!(mtxcli.get_option(TOKEN_KEY).as_deref(), Some("mat_access"));
+        assert!(transport.requests().is_empty());
+        cleanup(&mtxcli);
+    }
+
+    #[test]
+    fn label_rooms_sync_has_not_told_us_about() {
+        let transport = ScriptedTransport::new();
+        script_login(&transport);
+        let not_found = || Err(MatrixError::from_status(404, r#"{"errcode":"M_NOT_FOUND","error":"Event not found"}"#));
+        transport
+            .respond(Method::Get, "/joined_rooms", Ok(json!({ "joined_rooms": [ "!dm:localhost" ] })))
+            .respond(Method::Get, "/state/m.room.name/", not_found())
+            .respond(Method::Get, "/state/m.room.canonical_alias/", not_found())
+            .respond(Method::Get, "/state/m.room.encryption/", Ok(json!({ "algorithm": "m.megolm.v1.aes-sha2" })))
+            .respond(Method::Get, "/joined_members", Ok(json!({ "joined": {
+                ALICE: {},
+                "@bob:localhost": { "display_name": "Bob" },
+            } })));
+        let mut mtxcli = mtxcli("label", &transport);
+        mtxcli.login().unwrap();
+        mtxcli.load_rooms().unwrap();
+        assert_eq!(mtxcli.room_label("!dm:localhost"), "Bob");
+        assert!(mtxcli.rooms.get("!dm:localhost").unwrap().encrypted);
+        // only asked once
+        assert_eq!(mtxcli.room_label("!dm:localhost"), "Bob");
+        assert_eq!(transport.remaining(), 0);
+        assert_eq!(transport.requests().len(), 8);
+        cleanup(&mtxcli);
+    }
+}
@@ -0,0 +1,349 @@
+//! Matrix errors
+//!
+//! Errors returned by the homeserver (as per the spec's standard error
+//! response) as well as transport and protocol failures.
+
+use std::fmt;
+
+use ureq::serde_json::Value;
+
+/// Standard error codes from the Matrix client-server specification
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrCode {
+    /// Forbidden access, e.g. joining a room without permission
+    Forbidden,
+    /// The access token specified was not recognised
+    UnknownToken,
+    /// No access token was specified for the request
+    MissingToken,
+    /// Request contained valid JSON, but it was malformed in some way
+    BadJson,
+    /// Request did not contain valid JSON
+    NotJson,
+    /// No resource was found for this request
+    NotFound,
+    /// Too many requests have been sent in a short period of time
+    LimitExceeded,
+    /// The user ID or room alias is already taken
+    UserInUse,
+    /// The room alias is already taken
+    RoomInUse,
+    /// The request was not correctly authorized
+    Unauthorized,
+    /// The server does not recognise the request
+    Unrecognized,
+    /// Any other (or unknown) error code
+    Unknown(String),
+}
+
+impl ErrCode {
+    /// Returns the ErrCode for the `errcode` string
+    pub fn from_errcode(errcode: &str) -> Self {
+        match errcode {
+            "M_FORBIDDEN" => ErrCode::Forbidden,
+            "M_UNKNOWN_TOKEN" => ErrCode::UnknownToken,
+            "M_MISSING_TOKEN" => ErrCode::MissingToken,
+            "M_BAD_JSON" => ErrCode::BadJson,
+            "M_NOT_JSON" => ErrCode::NotJson,
+            "M_NOT_FOUND" => ErrCode::NotFound,
+            "M_LIMIT_EXCEEDED" => ErrCode::LimitExceeded,
+            "M_USER_IN_USE" => ErrCode::UserInUse,
+            "M_ROOM_IN_USE" => ErrCode::RoomInUse,
+            "M_UNAUTHORIZED" => ErrCode::Unauthorized,
+            "M_UNRECOGNIZED" => ErrCode::Unrecognized,
+            _ => ErrCode::Unknown(errcode.to_string()),
+        }
+    }
+
+    /// Returns the `errcode` string for this ErrCode
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrCode::Forbidden => "M_FORBIDDEN",
+            ErrCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ErrCode::MissingToken => "M_MISSING_TOKEN",
+            ErrCode::BadJson => "M_BAD_JSON",
+            ErrCode::NotJson => "M_NOT_JSON",
+            ErrCode::NotFound => "M_NOT_FOUND",
+            ErrCode::LimitExceeded => "M_LIMIT_EXCEEDED",
+            ErrCode::UserInUse => "M_USER_IN_USE",
+            ErrCode::RoomInUse => "M_ROOM_IN_USE",
+            ErrCode::Unauthorized => "M_UNAUTHORIZED",
+            ErrCode::Unrecognized => "M_UNRECOGNIZED",
+            ErrCode::Unknown(errcode) => errcode,
+        }
+    }
+}
+
+/// Errors from talking to a Matrix homeserver
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatrixError {
+    /// The homeserver returned a standard Matrix error response
+    Api {
+        status: u16,
+        errcode: ErrCode,
+        error: String,
+        retry_after_ms: Option<u64>,
+        soft_logout: bool,
+    },
+    /// The homeserver requires (further) user-interactive authentication:
+    /// info holds the session, flows, params and completed stages
+    InteractiveAuth { status: u16, info: Value },
+    /// The authorization server returned an OAuth 2.0 error response
+    OAuth { status: u16, error: String, description: String },
+    /// The homeserver returned an error status without a Matrix error body
+    Http { status: u16, body: String },
+    /// The request could not be delivered (DNS, connection, TLS...)
+    Transport(String),
+    /// The response was not what the spec says it should be
+    InvalidResponse(String),
+    /// The request could not be serialized
+    Serialize(String),
+    /// mtxcli is missing some configuration (e.g. password or room)
+    Config(String),
+}
+
+impl MatrixError {
+    /// Construct from an HTTP error status and the response body
+    pub fn from_status(status: u16, body: &str) -> Self {
+        if let Ok(Value::Object(object)) = ureq::serde_json::from_str::<Value>(body) {
+            if status == 401 && object.contains_key("flows") {
+                return MatrixError::InteractiveAuth {
+                    status,
+                    info: Value::Object(object),
+                };
+            }
+            if let Some(Value::String(errcode)) = object.get("errcode") {
+                let error = match object.get("error") {
+                    Some(Value::String(error)) => error.to_string(),
+                    _ => String::new(),
+                };
+                let retry_after_ms = match object.get("retry_after_ms") {
+                    Some(value) => value.as_u64(),
+                    None => None,
+                };
+                let soft_logout = match object.get("soft_logout") {
+                    Some(Value::Bool(soft_logout)) => *soft_logout,
+                    _ => false,
+                };
+                return MatrixError::Api {
+                    status,
+                    errcode: ErrCode::from_errcode(errcode),
+                    error,
+                    retry_after_ms,
+                    soft_logout,
+                };
+            }
+            if let Some(Value::String(error)) = object.get("error") {
+                let description = match object.get("error_description") {
+                    Some(Value::String(description)) => description.to_string(),
+                    _ => String::new(),
+                };
+                return MatrixError::OAuth {
+                    status,
+                    error: error.to_string(),
+                    description,
+                };
+            }
+        }
+        MatrixError::Http { status, body: body.to_string() }
+    }
+
+    /// Returns the HTTP status (if the server answered)
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            MatrixError::Api { status, .. } => Some(*status),
+            MatrixError::InteractiveAuth { status, .. } => Some(*status),
+            MatrixError::OAuth { status, .. } => Some(*status),
+            MatrixError::Http { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Returns the Matrix error code (if any)
+    pub fn errcode(&self) -> Option<&ErrCode> {
+        match self {
+            MatrixError::Api { errcode, .. } => Some(errcode),
+            _ => None,
+        }
+    }
+
+    /// Returns the user-interactive authentication info (if required)
+    pub fn interactive_auth(&self) -> Option<&Value> {
+        match self {
+            MatrixError::InteractiveAuth { info, .. } => Some(info),
+            _ => None,
+        }
+    }
+
+    /// Returns the OAuth 2.0 error code (if any)
+    pub fn oauth_error(&self) -> Option<&str> {
+        match self {
+            MatrixError::OAuth { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Does this error have the given Matrix error code?
+    pub fn is(&self, errcode: ErrCode) -> bool {
+        self.errcode() == Some(&errcode)
+    }
+
+    /// Is the access token missing or no longer valid?
+    pub fn is_unknown_token(&self) -> bool {
+        self.is(ErrCode::UnknownToken) || self.is(ErrCode::MissingToken)
+    }
+
+    /// Did the server ask the client to log in again (keeping its device)?
+    pub fn is_soft_logout(&self) -> bool {
+        matches!(self, MatrixError::Api { soft_logout: true, .. })
+    }
+
+    /// Was the access token invalidated (e.g. revoked) by the server?
+    pub fn is_logged_out(&self) -> bool {
+        match self {
+            MatrixError::Api { .. } => self.is_unknown_token() || self.is_soft_logout(),
+            MatrixError::Http { status, .. } => *status == 401,
+            _ => false,
+        }
+    }
+
+    /// Has the client been rate limited?
+    pub fn is_limit_exceeded(&self) -> bool {
+        self.is(ErrCode::LimitExceeded) || self.status() == Some(429)
+    }
+
+    /// Milliseconds the server asked us to wait before retrying
+    pub fn retry_after_ms(&self) -> Option<u64> {
+        match self {
+            MatrixError::Api { retry_after_ms, .. } => *retry_after_ms,
+            _ => None,
+        }
+    }
+}
+
+/// Display a MatrixError in a form suitable for the user
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixError::Api { status, errcode, error, .. } => {
+                if error.len() > 0 {
+                    write!(f, "{} ({}): {}", errcode.as_str(), status, error)
+                } else {
+                    write!(f, "{} ({})", errcode.as_str(), status)
+                }
+            },
+            MatrixError::InteractiveAuth { status, info } => {
+                match info.get("error") {
+                    Some(Value::String(error)) => {
+                        write!(f, "authentication required ({}): {}", status, error)
+                    },
+                    _ => write!(f, "authentication required ({})", status),
+                }
+            },
+            MatrixError::OAuth { status, error, description } => {
+                if description.len() > 0 {
+                    write!(f, "{} ({}): {}", error, status, description)
+                } else {
+                    write!(f, "{} ({})", error, status)
+                }
+            },
+            MatrixError::Http { status, body } => {
+                if body.len() > 0 {
+                    write!(f, "HTTP error {}: {}", status, body)
+                } else {
+                    write!(f, "HTTP error {}", status)
+                }
+            },
+            MatrixError::Transport(msg) => write!(f, "connection failed: {}", msg),
+            MatrixError::InvalidResponse(msg) => write!(f, "invalid response: {}", msg),
+            MatrixError::Serialize(msg) => write!(f, "unable to serialize request: {}", msg),
+            MatrixError::Config(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_error() {
+        let e = MatrixError::from_status(403, r#"{"errcode":"M_FORBIDDEN","error":"You are not invited to this room."}"#);
+        assert_eq!(e, MatrixError::Api {
+            status: 403,
+            errcode: ErrCode::Forbidden,
+            error: "You are not invited to this room.".to_string(),
+            retry_after_ms: None,
+            soft_logout: false,
+        });
+        assert!(e.is(ErrCode::Forbidden));
+        assert_eq!(e.to_string(), "M_FORBIDDEN (403): You are not invited to this room.");
+        let e = MatrixError::from_status(400, r#"{"errcode":"M_UNKNOWN_PROFILE"}"#);
+        assert_eq!(e.errcode(), Some(&ErrCode::Unknown("M_UNKNOWN_PROFILE".to_string())));
+        assert_eq!(e.to_string(), "M_UNKNOWN_PROFILE (400)");
+    }
+
+    #[test]
+    fn rate_limited() {
+        let e = MatrixError::from_status(429, r#"{"errcode":"M_LIMIT_EXCEEDED","error":"Too many requests","retry_after_ms":2000}"#);
+        assert!(e.is_limit_exceeded());
+        assert_eq!(e.retry_after_ms(), Some(2000));
+        // a proxy may rate limit without a Matrix error
+        let e = MatrixError::from_status(429, "");
+        assert!(e.is_limit_exceeded());
+        assert_eq!(e.retry_after_ms(), None);
+    }
+
+    #[test]
+    fn logged_out() {
+        let e = MatrixError::from_status(401, r#"{"errcode":"M_UNKNOWN_TOKEN","error":"Token expired","soft_logout":true}"#);
+        assert!(e.is_soft_logout());
+        assert!(e.is_unknown_token());
+        assert!(e.is_logged_out());
+        let e = MatrixError::from_status(401, r#"{"errcode":"M_MISSING_TOKEN"}"#);
+        assert!(! e.is_soft_logout());
+        assert!(e.is_logged_out());
+        assert!(MatrixError::from_status(401, "Unauthorized").is_logged_out());
+        assert!(! MatrixError::from_status(403, r#"{"errcode":"M_FORBIDDEN"}"#).is_logged_out());
+        assert!(! MatrixError::Transport("connection refused".to_string()).is_logged_out());
+    }
+
+    #[test]
+    fn interactive_auth() {
+        let body = r#"{"session":"S1","flows":[{"stages":["m.login.password"]}],"params":{},
+                       "errcode":"M_FORBIDDEN","error":"Invalid password"}"#;
+        let e = MatrixError::from_status(401, body);
+        let info = e.interactive_auth().unwrap();
+        assert_eq!(info["session"], "S1");
+        assert_eq!(e.status(), Some(401));
+        assert_eq!(e.errcode(), None);
+        assert_eq!(e.to_string(), "authentication required (401): Invalid password");
+        // flows only make a challenge with 401
+        let e = MatrixError::from_status(400, r#"{"flows":[],"errcode":"M_BAD_JSON"}"#);
+        assert!(e.interactive_auth().is_none());
+        assert!(e.is(ErrCode::BadJson));
+    }
+
+    #[test]
+    fn oauth_error() {
+        let e = MatrixError::from_status(400, r#"{"error":"authorization_pending","error_description":"waiting for the user"}"#);
+        assert_eq!(e.oauth_error(), Some("authorization_pending"));
+        assert_eq!(e.errcode(), None);
+        assert_eq!(e.to_string(), "authorization_pending (400): waiting for the user");
+        let e = MatrixError::from_status(400, r#"{"error":"slow_down"}"#);
+        assert_eq!(e, MatrixError::OAuth { status: 400, error: "slow_down".to_string(), description: String::new() });
+    }
+
+    #[test]
+    fn not_a_matrix_error() {
+        let e = MatrixError::from_status(502, "<html>Bad Gateway</html>");
+        assert_eq!(e, MatrixError::Http { status: 502, body: "<html>Bad Gateway</html>".to_string() });
+        assert_eq!(e.to_string(), "HTTP error 502: <html>Bad Gateway</html>");
+        assert_eq!(MatrixError::from_status(500, "").to_string(), "HTTP error 500");
+        // JSON, but not an error response
+        assert_eq!(MatrixError::from_status(404, r#"["M_NOT_FOUND"]"#).status(), Some(404));
+        assert!(MatrixError::from_status(404, r#"{"errcode":404}"#).errcode().is_none());
+    }
+}
@@ -3,19 +3,31 @@
 //! Enters interactive mode
 
 use std::io;
-use std::io::{Error,prelude::*};
+use std::io::Error;
 use std::boxed::Box;
 
 use crate::mtxcli::Mtxcli;
 
-mod get;      use get::*;
-mod help;     use help::*;
-mod login;    use login::*;
-mod logout;   use logout::*;
-mod quit;     use quit::*;
-mod set;      use set::*;
-mod status;   use status::*;
-mod unset;    use unset::*;
+mod create;     use create::*;
+mod deactivate; use deactivate::*;
+mod device;     use device::*;
+mod devices;    use devices::*;
+mod forget;     use forget::*;
+mod get;        use get::*;
+mod help;       use help::*;
+mod join;       use join::*;
+mod leave;      use leave::*;
+mod login;      use login::*;
+mod logout;     use logout::*;
+mod passwd;     use passwd::*;
+mod password;   use password::*;
+mod quit;       use quit::*;
+mod register;   use register::*;
+mod room;       use room::*;
+mod rooms;      use rooms::*;
+mod set;        use set::*;
+mod status;     use status::*;
+mod unset;      use unset::*;
 
 
 /// Interactive struct
@@ -36,11 +48,23 @@ impl<'a> Interactive<'a> {
 
     fn run(&mut self) -> Result<(), Error> {
         let mut commands: Vec<Box<dyn ShellCmdApi>> = Vec::new();
+        commands.push(Box::new(Create::new()));
+        commands.push(Box::new(Deactivate::new()));
+        commands.push(Box::new(Device::new()));
+        commands.push(Box::new(Devices::new()));
+        commands.push(Box::new(Forget::new()));
         commands.push(Box::new(Get::new()));
         commands.push(Box::new(Help::new()));
+        commands.push(Box::new(Join::new()));
+        commands.push(Box::new(Leave::new()));
         commands.push(Box::new(Login::new()));
         commands.push(Box::new(Logout::new()));
+        commands.push(Box::new(Passwd::new()));
+        commands.push(Box::new(Password::new()));
         commands.push(Box::new(Quit::new()));
+        commands.push(Box::new(Register::new()));
+        commands.push(Box::new(Room::new()));
+        commands.push(Box::new(Rooms::new()));
         commands.push(Box::new(Set::new()));
         commands.push(Box::new(Status::new()));
         commands.push(Box::new(Unset::new()));
@@ -51,8 +75,15 @@ impl<'a> Interactive<'a> {
         let stdin = io::stdin();
         self.mtxcli.prompt();
         println!("Welcome to {}. Type /help for available commands", self.mtxcli.app);
-        for line in stdin.lock().lines() {
-            let mut cmdline = line?;
+        loop {
+            // stdin is only locked while reading a line so that
+            // commands may ask questions (see Mtxcli::ask)
+            let mut cmdline = String::new();
+            if stdin.read_line(&mut cmdline)? == 0 {
+                break;
+            }
+            let end = cmdline.trim_end_matches(&['\n', '\r'][..]).len();
+            cmdline.truncate(end);
             let maybe_verb = tokenize(&mut cmdline);
             if let Some(verb) = maybe_verb {
                 // if verb starts with a slash then it's a command (else chat)
@@ -0,0 +1,181 @@
+use std::io::Error;
+use std::mem;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::mtxcli::web::{RoomOptions, ROOM_PRESETS, ROOM_VISIBILITIES};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Create {
+}
+impl Create {
+    pub fn new() -> Self {
+        Create {
+        }
+    }
+}
+
+// split on whitespace, except within "double quotes"
+fn split_words(args: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = ! quoted;
+                in_word = true;
+            },
+            c if c.is_whitespace() && ! quoted => {
+                if in_word {
+                    words.push(mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+// words which are not options make up the name
+fn parse_options(args: &str) -> Result<RoomOptions, String> {
+    let mut options = RoomOptions::default();
+    let mut name: Vec<String> = Vec::new();
+    for word in split_words(args) {
+        let (key, value) = match word.split_once('=') {
+            Some((key, value)) => (key, value.trim().to_string()),
+            None => {
+                match word.as_str() {
+                    "encrypted" => { options.set_encrypted(); },
+                    "public" | "private" => { options.visibility = Some(word); },
+                    // options are KEY=VALUE, not --KEY VALUE
+                    _ if word.starts_with("--") => {
+                        return Err(format!("unknown option: {}", word));
+                    },
+                    _ => { name.push(word); }
+                }
+                continue;
+            }
+        };
+        if value.len() == 0 {
+            return Err(format!("no value for {}", key));
+        }
+        match key {
+            "name" => { options.name = Some(value); },
+            "alias" => {
+                let alias = value.trim_start_matches('#');
+                if alias.contains(':') {
+                    return Err(format!("the alias must be on your server: {}", alias));
+                }
+                options.room_alias_name = Some(alias.to_string());
+            },
+            "topic" => { options.topic = Some(value); },
+            "visibility" if ROOM_VISIBILITIES.contains(&value.as_str()) => {
+                options.visibility = Some(value);
+            },
+            "preset" if ROOM_PRESETS.contains(&value.as_str()) => {
+                options.preset = Some(value);
+            },
+            "invite" => {
+                for user in value.split(',').filter(|user| user.len() > 0) {
+                    if ! user.starts_with('@') || ! user.contains(':') {
+                        return Err(format!("not a user_id: {}", user));
+                    }
+                    options.invite.push(user.to_string());
+                }
+            },
+            "visibility" => {
+                return Err(format!("visibility must be one of: {}", ROOM_VISIBILITIES.join(", ")));
+            },
+            "preset" => {
+                return Err(format!("preset must be one of: {}", ROOM_PRESETS.join(", ")));
+            },
+            _ => {
+                return Err(format!("unknown option: {}", key));
+            }
+        }
+    }
+    if name.len() > 0 {
+        options.name = Some(name.join(" "));
+    }
+    Ok(options)
+}
+
+impl<'a> ShellCmdApi<'a> for Create {
+    cmd_api!(create);
+
+    cmd_help!("/create [NAME] [alias=ALIAS] [topic=TOPIC] [public|private] [preset=PRESET] [encrypted] [invite=@USER,...]");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match parse_options(args) {
+            Ok(options) => {
+                env.mtxcli.create(&options).ok();
+            },
+            Err(e) => {
+                env.mtxcli.prompt();
+                println!("{}", e);
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_quoted_words() {
+        assert_eq!(split_words("  a  b\tc "), vec!["a", "b", "c"]);
+        assert_eq!(split_words(r#""Incident 42" topic="all hands""#),
+                   vec!["Incident 42", "topic=all hands"]);
+        // an empty quoted word is still a word
+        assert_eq!(split_words(r#"name="""#), vec!["name="]);
+        assert_eq!(split_words(r#""""#), vec![""]);
+        assert!(split_words("").is_empty());
+    }
+
+    #[test]
+    fn name_and_options() {
+        let options = parse_options(r#"Incident 42 alias=#inc-42 topic="all hands" public preset=public_chat encrypted"#).unwrap();
+        assert_eq!(options.name.as_deref(), Some("Incident 42"));
+        assert_eq!(options.room_alias_name.as_deref(), Some("inc-42"));
+        assert_eq!(options.topic.as_deref(), Some("all hands"));
+        assert_eq!(options.visibility.as_deref(), Some("public"));
+        assert_eq!(options.preset.as_deref(), Some("public_chat"));
+        assert!(options.is_encrypted());
+        assert!(options.invite.is_empty());
+        assert_eq!(parse_options("").unwrap(), RoomOptions::default());
+        let options = parse_options(r#"name="Incident 42""#).unwrap();
+        assert_eq!(options.name.as_deref(), Some("Incident 42"));
+    }
+
+    #[test]
+    fn invite() {
+        let options = parse_options("invite=@bob:localhost,,@carol:example.org").unwrap();
+        assert_eq!(options.invite, vec!["@bob:localhost", "@carol:example.org"]);
+        assert!(parse_options("invite=bob").is_err());
+        assert!(parse_options("invite=@bob").is_err());
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert_eq!(parse_options("color=red").unwrap_err(), "unknown option: color");
+        assert_eq!(parse_options("--preset public_chat").unwrap_err(), "unknown option: --preset");
+        assert_eq!(parse_options("topic=").unwrap_err(), "no value for topic");
+        assert_eq!(parse_options(r#"topic=" ""#).unwrap_err(), "no value for topic");
+        assert!(parse_options("preset=open").unwrap_err().starts_with("preset must be one of"));
+        assert!(parse_options("visibility=hidden").unwrap_err().starts_with("visibility must be one of"));
+        assert_eq!(parse_options("alias=inc:example.org").unwrap_err(),
+                   "the alias must be on your server: inc:example.org");
+    }
+}
@@ -0,0 +1,36 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Deactivate {
+}
+impl Deactivate {
+    pub fn new() -> Self {
+        Deactivate {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Deactivate {
+    cmd_api!(deactivate);
+
+    cmd_help!("/deactivate [erase]");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            "" => {
+                env.mtxcli.deactivate(false).ok();
+            },
+            "erase" => {
+                env.mtxcli.deactivate(true).ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
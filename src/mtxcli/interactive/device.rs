@@ -0,0 +1,48 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Device {
+}
+impl Device {
+    pub fn new() -> Self {
+        Device {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Device {
+    cmd_api!(device);
+
+    cmd_help!("/device rename DEVICE_ID NAME | /device delete DEVICE_ID...");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        let (action, rest) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        match action {
+            "rename" => {
+                match rest.trim().split_once(' ') {
+                    Some((device_id, name)) if name.trim().len() > 0 => {
+                        env.mtxcli.rename_device(device_id, name.trim()).ok();
+                        return Ok(false);
+                    },
+                    _ => { }
+                }
+            },
+            "delete" => {
+                let device_ids: Vec<String> = rest.split_whitespace()
+                    .map(|device_id| device_id.to_string())
+                    .collect();
+                if device_ids.len() > 0 {
+                    env.mtxcli.delete_devices(&device_ids).ok();
+                    return Ok(false);
+                }
+            },
+            _ => { }
+        }
+        env.mtxcli.prompt();
+        println!("{}", self.help());
+        Ok(false)
+    }
+}
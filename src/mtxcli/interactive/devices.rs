@@ -0,0 +1,33 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Devices {
+}
+impl Devices {
+    pub fn new() -> Self {
+        Devices {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Devices {
+    cmd_api!(devices);
+
+    cmd_help!("/devices");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            "" => {
+                env.mtxcli.list_devices().ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,33 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Forget {
+}
+impl Forget {
+    pub fn new() -> Self {
+        Forget {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Forget {
+    cmd_api!(forget);
+
+    cmd_help!("/forget #ALIAS|!ROOM_ID");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            room if room.len() > 0 && ! room.contains(' ') => {
+                env.mtxcli.forget(room).ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,35 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Join {
+}
+impl Join {
+    pub fn new() -> Self {
+        Join {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Join {
+    cmd_api!(join);
+
+    cmd_help!("/join #ALIAS|!ROOM_ID [SERVER...]");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        let mut words = args.split_whitespace();
+        match words.next() {
+            Some(room) => {
+                let via: Vec<String> = words.map(|server| server.to_string()).collect();
+                env.mtxcli.join(room, &via).ok();
+            },
+            None => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
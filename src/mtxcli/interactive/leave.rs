@@ -0,0 +1,25 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Leave {
+}
+impl Leave {
+    pub fn new() -> Self {
+        Leave {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Leave {
+    cmd_api!(leave);
+
+    cmd_help!("/leave [ROOM] [REASON]");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        env.mtxcli.leave(args.trim()).ok();
+        Ok(false)
+    }
+}
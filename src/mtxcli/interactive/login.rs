@@ -16,10 +16,24 @@ impl Login {
 impl<'a> ShellCmdApi<'a> for Login {
     cmd_api!(login);
 
-    cmd_help!("/login");
+    cmd_help!("/login [sso|oidc]");
 
-    fn process(&self, _args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
-        env.mtxcli.login();
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            "" => {
+                env.mtxcli.login().ok();
+            },
+            "sso" => {
+                env.mtxcli.login_sso().ok();
+            },
+            "oidc" => {
+                env.mtxcli.login_oidc().ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
         Ok(false)
     }
 }
@@ -16,10 +16,21 @@ impl Logout {
 impl<'a> ShellCmdApi<'a> for Logout {
     cmd_api!(logout);
 
-    cmd_help!("/logout");
+    cmd_help!("/logout [all]");
 
-    fn process(&self, _args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
-        env.mtxcli.logout();
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            "" => {
+                env.mtxcli.logout(false).ok();
+            },
+            "all" => {
+                env.mtxcli.logout(true).ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
         Ok(false)
     }
 }
@@ -0,0 +1,36 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Passwd {
+}
+impl Passwd {
+    pub fn new() -> Self {
+        Passwd {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Passwd {
+    cmd_api!(passwd);
+
+    cmd_help!("/passwd [logout]");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            "" => {
+                env.mtxcli.change_password(false).ok();
+            },
+            "logout" => {
+                env.mtxcli.change_password(true).ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
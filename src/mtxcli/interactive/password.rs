@@ -0,0 +1,33 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Password {
+}
+impl Password {
+    pub fn new() -> Self {
+        Password {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Password {
+    cmd_api!(password);
+
+    cmd_help!("/password");
+
+    fn process(&self, _args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match env.mtxcli.ask_secret("password:") {
+            Some(password) if password.len() > 0 => {
+                env.mtxcli.set_password(&password);
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("password not changed");
+            }
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,25 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Register {
+}
+impl Register {
+    pub fn new() -> Self {
+        Register {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Register {
+    cmd_api!(register);
+
+    cmd_help!("/register");
+
+    fn process(&self, _args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        env.mtxcli.register().ok();
+        Ok(false)
+    }
+}
@@ -0,0 +1,33 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Room {
+}
+impl Room {
+    pub fn new() -> Self {
+        Room {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Room {
+    cmd_api!(room);
+
+    cmd_help!("/room [ALIAS | ROOM_ID | INDEX]");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            room if ! room.contains(' ') => {
+                env.mtxcli.switch_room(room).ok();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
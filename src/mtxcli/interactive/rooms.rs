@@ -0,0 +1,33 @@
+use std::io::Error;
+
+use crate::mtxcli::interactive::{ShellCmdApi,Interactive};
+use crate::{cmd_api,cmd_help};
+
+#[derive(Debug)]
+pub struct Rooms {
+}
+impl Rooms {
+    pub fn new() -> Self {
+        Rooms {
+        }
+    }
+}
+
+impl<'a> ShellCmdApi<'a> for Rooms {
+    cmd_api!(rooms);
+
+    cmd_help!("/rooms");
+
+    fn process(&self, args: &str, env: &mut Interactive, _commands: &Vec<Box<dyn ShellCmdApi>>) -> Result<bool, Error> {
+        match args.trim() {
+            "" => {
+                env.mtxcli.list_rooms();
+            },
+            _ => {
+                env.mtxcli.prompt();
+                println!("{}", self.help());
+            }
+        }
+        Ok(false)
+    }
+}
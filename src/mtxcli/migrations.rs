@@ -9,6 +9,7 @@ use crate::mtxcli::{Mtxcli,VERSION_KEY};
 
 mod v0_5_0;      use v0_5_0::*;
 mod v0_6_0;      use v0_6_0::*;
+mod v0_7_0;      use v0_7_0::*;
 
 const DEFAULT_VERSION: &str = "0";
 
@@ -49,6 +50,7 @@ pub fn run_migrations(mtxcli: &mut Mtxcli) {
         let mut migrations: Vec<Box<dyn MigrationApi>> = Vec::new();
         migrations.push(Box::new(V0_5_0::new()));
         migrations.push(Box::new(V0_6_0::new()));
+        migrations.push(Box::new(V0_7_0::new()));
         for migration in migrations.iter() {
             if migration.applies(&version) {
                 match migration.process(mtxcli) {
@@ -0,0 +1,62 @@
+use std::io::Error;
+
+use crate::mtxcli::{Mtxcli,FILTER_KEY,PASSWORD_KEY,REFRESH_TOKEN_KEY,TOKEN_KEY};
+use crate::mtxcli::migrations::MigrationApi;
+use crate::migration_api;
+
+#[derive(Debug)]
+pub struct V0_7_0 {
+}
+impl V0_7_0 {
+    pub fn new() -> Self {
+        V0_7_0 {
+        }
+    }
+}
+
+impl<'a> MigrationApi<'a> for V0_7_0 {
+    migration_api!(0.7.0);
+
+    fn process(&self, mtxcli: &mut Mtxcli) -> Result<bool, Error> {
+        debug!("Running migration for: {}", self.version());
+        // the password and tokens are no longer stored in plaintext
+        for key in [PASSWORD_KEY, TOKEN_KEY, REFRESH_TOKEN_KEY] {
+            mtxcli.migrate_secret(key)?;
+        }
+        // the new filter follows all the joined rooms
+        mtxcli.unset(FILTER_KEY)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mtxcli::secrets::SecretStore;
+    use crate::mtxcli::tests::{cleanup, mtxcli};
+    use crate::mtxcli::transport::scripted::ScriptedTransport;
+
+    #[test]
+    fn move_secrets_into_the_store() {
+        let transport = ScriptedTransport::new();
+        let mut mtxcli = mtxcli("v0_7_0", &transport);
+        mtxcli.write_key(PASSWORD_KEY, "secret").unwrap();
+        mtxcli.write_key(TOKEN_KEY, "token1").unwrap();
+        mtxcli.write_key(REFRESH_TOKEN_KEY, "refresh1").unwrap();
+        mtxcli.write_key(FILTER_KEY, "7").unwrap();
+        mtxcli.secrets.create("passphrase").unwrap();
+        assert!(V0_7_0::new().process(&mut mtxcli).unwrap());
+        let config_dir = Path::new(&mtxcli.system.config_dir);
+        for key in [PASSWORD_KEY, TOKEN_KEY, REFRESH_TOKEN_KEY, FILTER_KEY] {
+            assert!(! config_dir.join(key).exists(), "{} was not removed", key);
+        }
+        let mut store = SecretStore::new(config_dir);
+        store.unlock("passphrase").unwrap();
+        assert_eq!(store.get(PASSWORD_KEY).as_deref(), Some("secret"));
+        assert_eq!(store.get(TOKEN_KEY).as_deref(), Some("token1"));
+        assert_eq!(store.get(REFRESH_TOKEN_KEY).as_deref(), Some("refresh1"));
+        cleanup(&mtxcli);
+    }
+}
@@ -0,0 +1,334 @@
+//! OAuth 2.0 / OpenID Connect authentication
+//!
+//! Homeservers using next-generation auth (MSC3861) delegate login to
+//! an OpenID Connect issuer. As the Precursor has no browser we use the
+//! device authorization grant (RFC 8628): the user approves the login
+//! on another device while we poll the issuer for the tokens.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use ureq::serde_json::Value;
+
+use crate::mtxcli::error::MatrixError;
+use crate::mtxcli::transport::MatrixTransport;
+use crate::mtxcli::url;
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const REFRESH_TOKEN_GRANT: &str = "refresh_token";
+
+/// Scopes for full access to the client-server API as a device (MSC2967)
+const SCOPE_API: &str = "urn:matrix:org.matrix.msc2967.client:api:*";
+const SCOPE_DEVICE: &str = "urn:matrix:org.matrix.msc2967.client:device:";
+
+const CLIENT_NAME: &str = "mtxcli";
+const CLIENT_URI: &str = "https://github.com/betrusted-io/mtxcli";
+
+/// Seconds between polls unless the issuer says otherwise
+const POLL_INTERVAL: u64 = 5; // s
+/// Seconds to add to the interval when asked to slow down
+const POLL_SLOW_DOWN: u64 = 5; // s
+
+const DEVICE_ID_LENGTH: usize = 10;
+
+/// The issuer metadata (the parts we use)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AuthMetadata {
+    pub issuer: String,
+    pub token_endpoint: String,
+    pub registration_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClientRegistration {
+    client_name: String,
+    client_uri: String,
+    application_type: String,
+    token_endpoint_auth_method: String,
+    grant_types: Vec<String>,
+    response_types: Vec<String>,
+}
+
+impl ClientRegistration {
+    pub fn new() -> Self {
+        ClientRegistration {
+            client_name: CLIENT_NAME.to_string(),
+            client_uri: CLIENT_URI.to_string(),
+            application_type: "native".to_string(),
+            token_endpoint_auth_method: "none".to_string(),
+            grant_types: vec![DEVICE_CODE_GRANT.to_string(), REFRESH_TOKEN_GRANT.to_string()],
+            response_types: Vec::new(),
+        }
+    }
+}
+
+/// The answer to a device authorization request
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    pub interval: Option<u64>,
+}
+
+/// Tokens issued by the token endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+fn parse<T: serde::de::DeserializeOwned>(value: Value, what: &str) -> Result<T, MatrixError> {
+    ureq::serde_json::from_value(value)
+        .map_err(|e| MatrixError::InvalidResponse(format!("invalid {}: {}", what, e)))
+}
+
+/// Discover the issuer of the homeserver and return its metadata
+pub fn get_auth_metadata(transport: &dyn MatrixTransport, server: &str) -> Result<AuthMetadata, MatrixError> {
+    let url = url::build(server, &["_matrix", "client", "v1", "auth_metadata"], &[]);
+    match transport.get_json(&url) {
+        Ok(value) => {
+            return parse(value, "auth_metadata");
+        },
+        Err(e) if e.status() == Some(404) || e.status() == Some(400) => {
+            debug!("no auth_metadata ({}), trying auth_issuer", e);
+        },
+        Err(e) => {
+            return Err(e);
+        }
+    }
+    // older servers only name the issuer (MSC2965)
+    let url = url::build(server, &["_matrix", "client", "unstable", "org.matrix.msc2965", "auth_issuer"], &[]);
+    let value = transport.get_json(&url)?;
+    let issuer = match value.get("issuer") {
+        Some(Value::String(issuer)) => issuer.to_string(),
+        _ => {
+            return Err(MatrixError::InvalidResponse("no issuer for auth_issuer".to_string()));
+        }
+    };
+    let url = url::build(&issuer, &[".well-known", "openid-configuration"], &[]);
+    parse(transport.get_json(&url)?, "openid-configuration")
+}
+
+/// Register mtxcli as a public client, returns the client_id
+pub fn register_client(transport: &dyn MatrixTransport, metadata: &AuthMetadata) -> Result<String, MatrixError> {
+    let url = match &metadata.registration_endpoint {
+        Some(url) => url,
+        None => {
+            return Err(MatrixError::Config(format!("{} does not support client registration",
+                                                   metadata.issuer)));
+        }
+    };
+    let request_body = ureq::serde_json::to_string(&ClientRegistration::new())
+        .map_err(|e| MatrixError::Serialize(e.to_string()))?;
+    let value = transport.post_string(url, &request_body)?;
+    if let Some(Value::String(client_id)) = value.get("client_id") {
+        Ok(client_id.to_string())
+    } else {
+        Err(MatrixError::InvalidResponse("no client_id for register_client".to_string()))
+    }
+}
+
+/// Returns a new random device_id
+pub fn gen_device_id() -> String {
+    let mut bytes = [0u8; DEVICE_ID_LENGTH];
+    getrandom::getrandom(&mut bytes).expect("couldn't get random data");
+    bytes.iter().map(|b| (b'A' + b % 26) as char).collect()
+}
+
+/// Start the device authorization grant for the device_id
+pub fn request_device_authorization(transport: &dyn MatrixTransport, metadata: &AuthMetadata,
+                                    client_id: &str, device_id: &str)
+                                    -> Result<DeviceAuthorization, MatrixError> {
+    let url = match &metadata.device_authorization_endpoint {
+        Some(url) => url,
+        None => {
+            return Err(MatrixError::Config(format!("{} does not support the device authorization grant",
+                                                   metadata.issuer)));
+        }
+    };
+    let scope = format!("{} {}{}", SCOPE_API, SCOPE_DEVICE, device_id);
+    let form = url::form(&[("client_id", client_id), ("scope", &scope)]);
+    parse(transport.post_form(url, &form)?, "device authorization")
+}
+
+/// Exchange the refresh token for new tokens at the token endpoint
+pub fn refresh(transport: &dyn MatrixTransport, token_endpoint: &str,
+               client_id: &str, refresh_token: &str) -> Result<TokenResponse, MatrixError> {
+    let form = url::form(&[("grant_type", REFRESH_TOKEN_GRANT),
+                           ("refresh_token", refresh_token),
+                           ("client_id", client_id)]);
+    parse(transport.post_form(token_endpoint, &form)?, "token response")
+}
+
+/// Revoke the token (and with a refresh token, the whole session)
+pub fn revoke(transport: &dyn MatrixTransport, metadata: &AuthMetadata,
+              client_id: &str, token: &str, token_type_hint: &str) -> Result<(), MatrixError> {
+    let url = match &metadata.revocation_endpoint {
+        Some(url) => url,
+        None => {
+            return Err(MatrixError::Config(format!("{} does not support token revocation",
+                                                   metadata.issuer)));
+        }
+    };
+    let form = url::form(&[("token", token),
+                           ("token_type_hint", token_type_hint),
+                           ("client_id", client_id)]);
+    transport.post_form(url, &form)?;
+    Ok(())
+}
+
+/// Poll the token endpoint until the user has approved (or denied) the login
+pub fn poll_device_token(transport: &dyn MatrixTransport, metadata: &AuthMetadata,
+                         client_id: &str, authorization: &DeviceAuthorization)
+                         -> Result<TokenResponse, MatrixError> {
+    poll_device_token_with(transport, metadata, client_id, authorization, thread::sleep)
+}
+
+// waits between polls with sleep (so that the tests need not wait)
+fn poll_device_token_with(transport: &dyn MatrixTransport, metadata: &AuthMetadata,
+                          client_id: &str, authorization: &DeviceAuthorization,
+                          mut sleep: impl FnMut(Duration)) -> Result<TokenResponse, MatrixError> {
+    let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = authorization.interval.unwrap_or(POLL_INTERVAL);
+    let form = url::form(&[("grant_type", DEVICE_CODE_GRANT),
+                           ("device_code", &authorization.device_code),
+                           ("client_id", client_id)]);
+    loop {
+        sleep(Duration::from_secs(interval));
+        match transport.post_form(&metadata.token_endpoint, &form) {
+            Ok(value) => {
+                return parse(value, "token response");
+            },
+            Err(e) if e.oauth_error() == Some("authorization_pending") => { },
+            Err(e) if e.oauth_error() == Some("slow_down") => {
+                interval += POLL_SLOW_DOWN;
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(MatrixError::Config("the login was not approved in time".to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ureq::serde_json::json;
+
+    use super::*;
+    use crate::mtxcli::transport::Method;
+    use crate::mtxcli::transport::scripted::ScriptedTransport;
+
+    fn metadata() -> AuthMetadata {
+        AuthMetadata {
+            issuer: "http://localhost/".to_string(),
+            token_endpoint: "http://localhost/oauth2/token".to_string(),
+            registration_endpoint: Some("http://localhost/oauth2/registration".to_string()),
+            device_authorization_endpoint: Some("http://localhost/oauth2/device".to_string()),
+            revocation_endpoint: Some("http://localhost/oauth2/revoke".to_string()),
+        }
+    }
+
+    fn authorization(expires_in: u64, interval: Option<u64>) -> DeviceAuthorization {
+        DeviceAuthorization {
+            device_code: "dc1".to_string(),
+            user_code: "ABCD-EFGH".to_string(),
+            verification_uri: "http://localhost/link".to_string(),
+            verification_uri_complete: None,
+            expires_in,
+            interval,
+        }
+    }
+
+    fn oauth_error(error: &str) -> Result<Value, MatrixError> {
+        Err(MatrixError::from_status(400, &json!({ "error": error }).to_string()))
+    }
+
+    // polls without waiting, returns the result and the waits
+    fn poll(transport: &ScriptedTransport, authorization: &DeviceAuthorization)
+            -> (Result<TokenResponse, MatrixError>, Vec<u64>) {
+        let mut waits = Vec::new();
+        let result = poll_device_token_with(transport, &metadata(), "client1", authorization,
+                                            |wait| waits.push(wait.as_secs()));
+        (result, waits)
+    }
+
+    #[test]
+    fn poll_until_approved() {
+        let transport = ScriptedTransport::new();
+        transport
+            .respond(Method::Post, "/oauth2/token", oauth_error("authorization_pending"))
+            .respond(Method::Post, "/oauth2/token", oauth_error("authorization_pending"))
+            .respond(Method::Post, "/oauth2/token", Ok(json!({
+                "access_token": "mat_access", "refresh_token": "mar_refresh",
+                "token_type": "Bearer", "expires_in": 300,
+            })));
+        let (result, waits) = poll(&transport, &authorization(600, Some(2)));
+        let tokens = result.unwrap();
+        assert_eq!(tokens.access_token, "mat_access");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("mar_refresh"));
+        assert_eq!(waits, vec![2, 2, 2]);
+        let form = transport.requests()[0].body.clone().unwrap();
+        assert_eq!(form, "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code\
+                          &device_code=dc1&client_id=client1");
+    }
+
+    #[test]
+    fn slow_down() {
+        let transport = ScriptedTransport::new();
+        transport
+            .respond(Method::Post, "/oauth2/token", oauth_error("slow_down"))
+            .respond(Method::Post, "/oauth2/token", oauth_error("authorization_pending"))
+            .respond(Method::Post, "/oauth2/token", oauth_error("slow_down"))
+            .respond(Method::Post, "/oauth2/token", Ok(json!({ "access_token": "mat_access" })));
+        // without an interval from the issuer
+        let (result, waits) = poll(&transport, &authorization(600, None));
+        assert!(result.unwrap().refresh_token.is_none());
+        assert_eq!(waits, vec![POLL_INTERVAL, POLL_INTERVAL + POLL_SLOW_DOWN,
+                               POLL_INTERVAL + POLL_SLOW_DOWN, POLL_INTERVAL + 2 * POLL_SLOW_DOWN]);
+    }
+
+    #[test]
+    fn denied_or_expired() {
+        for error in ["access_denied", "expired_token"] {
+            let transport = ScriptedTransport::new();
+            transport
+                .respond(Method::Post, "/oauth2/token", oauth_error("authorization_pending"))
+                .respond(Method::Post, "/oauth2/token", oauth_error(error))
+                .respond(Method::Post, "/oauth2/token", Ok(json!({ "access_token": "mat_access" })));
+            let (result, waits) = poll(&transport, &authorization(600, Some(1)));
+            assert_eq!(result.unwrap_err().oauth_error(), Some(error));
+            assert_eq!(waits.len(), 2);
+            assert_eq!(transport.remaining(), 1);
+        }
+    }
+
+    #[test]
+    fn not_approved_in_time() {
+        let transport = ScriptedTransport::new();
+        transport.respond(Method::Post, "/oauth2/token", oauth_error("authorization_pending"));
+        let (result, _) = poll(&transport, &authorization(0, Some(1)));
+        assert_eq!(result.unwrap_err(), MatrixError::Config("the login was not approved in time".to_string()));
+    }
+
+    #[test]
+    fn revoke_refresh_token() {
+        let transport = ScriptedTransport::new();
+        transport.respond(Method::Post, "/oauth2/revoke", Ok(json!({})));
+        revoke(&transport, &metadata(), "client1", "mar_refresh", "refresh_token").unwrap();
+        assert_eq!(transport.requests()[0].body.as_deref(),
+                   Some("token=mar_refresh&token_type_hint=refresh_token&client_id=client1"));
+        let mut metadata = metadata();
+        metadata.revocation_endpoint = None;
+        assert!(revoke(&transport, &metadata, "client1", "mar_refresh", "refresh_token").is_err());
+        assert_eq!(transport.requests().len(), 1);
+    }
+}
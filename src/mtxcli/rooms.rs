@@ -0,0 +1,371 @@
+//! Joined rooms
+//!
+//! All the rooms the user has joined are followed at once: we learn
+//! them from /joined_rooms and keep their labels up to date from sync
+//! (asking for the state of a room only when sync has not told us).
+//! They are numbered in the order we learned about them so that the
+//! user may switch to a room with `/room N`.
+
+use std::collections::BTreeMap;
+
+use crate::mtxcli::web::{self, RoomUpdate, SyncResponse};
+
+/// Heroes shown by name before "and N others"
+const MAX_HEROES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Room {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub canonical_alias: Option<String>,
+    pub encrypted: bool,
+    /// from the room summary (to name rooms without a name or alias)
+    pub heroes: Vec<String>,
+    pub joined_member_count: u64,
+    pub invited_member_count: u64,
+    /// display names of the members we know of (e.g. the heroes)
+    display_names: BTreeMap<String, String>,
+    pub notification_count: u64,
+    pub highlight_count: u64,
+    /// set once we know enough to label the room
+    labelled: bool,
+}
+
+/// "A", "A and B", "A, B and C"
+fn join_names(names: &[String]) -> String {
+    match names.split_last() {
+        Some((last, first)) if first.len() > 0 => format!("{} and {}", first.join(", "), last),
+        Some((last, _)) => last.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Could room (as typed by the user) be the alias of a room without
+/// the '#', e.g. "test" or "test:example.org"?
+pub fn may_be_alias(room: &str) -> bool {
+    let (local, server_name) = room.split_once(':').unwrap_or((room, "x"));
+    local.len() > 0 && server_name.len() > 0
+        && local.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._=-/".contains(c))
+}
+
+impl Room {
+    pub fn new(room_id: &str) -> Self {
+        Room {
+            room_id: room_id.to_string(),
+            name: None,
+            canonical_alias: None,
+            encrypted: false,
+            heroes: Vec::new(),
+            joined_member_count: 0,
+            invited_member_count: 0,
+            display_names: BTreeMap::new(),
+            notification_count: 0,
+            highlight_count: 0,
+            labelled: false,
+        }
+    }
+
+    // the display name of the member (else the localpart)
+    fn member_name(&self, user_id: &str) -> String {
+        match self.display_names.get(user_id) {
+            Some(display_name) => display_name.to_string(),
+            None => web::get_username(user_id),
+        }
+    }
+
+    /// The display name of the room (as calculated by the spec): its
+    /// name, else its canonical alias, else made up from the heroes
+    pub fn display_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.to_string();
+        }
+        if let Some(alias) = &self.canonical_alias {
+            return alias.to_string();
+        }
+        let heroes: Vec<String> = self.heroes.iter()
+            .take(MAX_HEROES)
+            .map(|hero| self.member_name(hero))
+            .collect();
+        // the members other than us
+        let others = (self.joined_member_count + self.invited_member_count).saturating_sub(1) as usize;
+        if heroes.len() == 0 {
+            // without a summary there is nothing better
+            self.room_id.to_string()
+        } else if others == 0 {
+            format!("Empty Room (was {})", join_names(&heroes))
+        } else if heroes.len() < others {
+            format!("{}, and {} others", heroes.join(", "), others - heroes.len())
+        } else {
+            join_names(&heroes)
+        }
+    }
+
+    /// How the room is shown: its alias (without our server name),
+    /// else its display name
+    pub fn label(&self, server_name: &str) -> String {
+        if let Some(alias) = &self.canonical_alias {
+            match alias.strip_suffix(server_name).and_then(|alias| alias.strip_suffix(':')) {
+                Some(local) if server_name.len() > 0 => local.to_string(),
+                _ => alias.to_string(),
+            }
+        } else {
+            self.display_name()
+        }
+    }
+
+    /// Does room (as typed by the user) name this room?
+    fn is(&self, room: &str, server_name: &str) -> bool {
+        if room == self.room_id {
+            return true;
+        }
+        let alias = match &self.canonical_alias {
+            Some(alias) => alias,
+            None => {
+                return false;
+            }
+        };
+        let mut full = String::new();
+        if ! room.starts_with('#') {
+            full.push('#');
+        }
+        full.push_str(room);
+        if ! room.contains(':') {
+            full.push(':');
+            full.push_str(server_name);
+        }
+        *alias == full
+    }
+
+    fn update(&mut self, update: &RoomUpdate) {
+        // an empty value means it was removed
+        if let Some(name) = &update.name {
+            self.name = Some(name.to_string()).filter(|name| name.len() > 0);
+        }
+        if let Some(alias) = &update.canonical_alias {
+            self.canonical_alias = Some(alias.to_string()).filter(|alias| alias.len() > 0);
+        }
+        self.encrypted |= update.encrypted;
+        self.labelled |= update.name.is_some() || update.canonical_alias.is_some() || update.heroes.is_some();
+        for (user_id, display_name) in update.members.iter() {
+            match display_name {
+                Some(display_name) => {
+                    self.display_names.insert(user_id.to_string(), display_name.to_string());
+                },
+                None => {
+                    self.display_names.remove(user_id);
+                }
+            }
+        }
+        if let Some(heroes) = &update.heroes {
+            self.heroes = heroes.clone();
+        }
+        if let Some(count) = update.joined_member_count {
+            self.joined_member_count = count;
+        }
+        if let Some(count) = update.invited_member_count {
+            self.invited_member_count = count;
+        }
+        if let Some(count) = update.notification_count {
+            self.notification_count = count;
+        }
+        if let Some(count) = update.highlight_count {
+            self.highlight_count = count;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Rooms {
+    rooms: Vec<Room>,
+    /// set once we know all the joined rooms
+    loaded: bool,
+}
+
+impl Rooms {
+    pub fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    /// Start over with the joined rooms (keeping what we know of them)
+    pub fn load(&mut self, room_ids: &[String]) {
+        self.rooms.retain(|room| room_ids.contains(&room.room_id));
+        for room_id in room_ids.iter() {
+            self.add(room_id);
+        }
+        self.loaded = true;
+    }
+
+    /// Forget the rooms (e.g. of another user)
+    pub fn clear(&mut self) {
+        self.rooms.clear();
+        self.loaded = false;
+    }
+
+    /// Follow the changes (and newly joined or left rooms) of a sync
+    pub fn update(&mut self, sync: &SyncResponse) {
+        for update in sync.joined.iter() {
+            self.update_room(update);
+        }
+        self.rooms.retain(|room| ! sync.left.contains(&room.room_id));
+    }
+
+    /// A room we just joined (until sync tells us more)
+    pub fn add(&mut self, room_id: &str) {
+        if self.get(room_id).is_none() {
+            self.rooms.push(Room::new(room_id));
+        }
+    }
+
+    /// A room we just left
+    pub fn remove(&mut self, room_id: &str) {
+        self.rooms.retain(|room| room.room_id != room_id);
+    }
+
+    /// Follow the changes of one room (e.g. its state)
+    pub fn update_room(&mut self, update: &RoomUpdate) {
+        match self.rooms.iter_mut().find(|room| room.room_id == update.room_id) {
+            Some(room) => {
+                room.update(update);
+            },
+            None => {
+                let mut room = Room::new(&update.room_id);
+                room.update(update);
+                self.rooms.push(room);
+            }
+        }
+    }
+
+    /// Is this a joined room we cannot label yet?
+    pub fn needs_state(&self, room_id: &str) -> bool {
+        matches!(self.get(room_id), Some(room) if ! room.labelled)
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<&Room> {
+        self.rooms.iter().find(|room| room.room_id == room_id)
+    }
+
+    /// The rooms in order (the index for /room is one more)
+    pub fn iter(&self) -> impl Iterator<Item = &Room> {
+        self.rooms.iter()
+    }
+
+    /// Returns the room by index (starting at 1), room_id or alias
+    pub fn find(&self, room: &str, server_name: &str) -> Option<&Room> {
+        match room.parse::<usize>() {
+            Ok(i) if i > 0 => self.rooms.get(i - 1),
+            _ => self.rooms.iter().find(|r| r.is(room, server_name)),
+        }
+    }
+
+    /// The label of the room (its room_id if unknown)
+    pub fn label(&self, room_id: &str, server_name: &str) -> String {
+        match self.get(room_id) {
+            Some(room) => room.label(server_name),
+            None => room_id.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_NAME: &str = "localhost";
+
+    // a room named after its heroes (with their display names), the
+    // joined members including us
+    fn room(heroes: &[&str], joined_member_count: u64) -> Room {
+        let members: Vec<(String, Option<String>)> = heroes.iter()
+            .map(|hero| (format!("@{}:localhost", hero.to_lowercase()), Some(hero.to_string())))
+            .collect();
+        let mut room = Room::new("!room:localhost");
+        room.update(&RoomUpdate {
+            room_id: room.room_id.clone(),
+            heroes: Some(members.iter().map(|(user_id, _)| user_id.to_string()).collect()),
+            members,
+            joined_member_count: Some(joined_member_count),
+            invited_member_count: Some(0),
+            ..Default::default()
+        });
+        room
+    }
+
+    #[test]
+    fn name_beats_canonical_alias() {
+        let mut room = room(&["Bob"], 2);
+        room.canonical_alias = Some("#test:localhost".to_string());
+        assert_eq!(room.display_name(), "#test:localhost");
+        room.name = Some("Test".to_string());
+        assert_eq!(room.display_name(), "Test");
+        // the label prefers the alias, without our server name
+        assert_eq!(room.label(SERVER_NAME), "#test");
+        assert_eq!(room.label("example.org"), "#test:localhost");
+        // an empty name removes it
+        room.update(&RoomUpdate { name: Some(String::new()), ..Default::default() });
+        assert_eq!(room.display_name(), "#test:localhost");
+    }
+
+    #[test]
+    fn named_after_heroes() {
+        assert_eq!(room(&["Bob"], 2).display_name(), "Bob");
+        assert_eq!(room(&["Bob", "Carol"], 3).display_name(), "Bob and Carol");
+        assert_eq!(room(&["Bob", "Carol"], 4).display_name(), "Bob, Carol, and 1 others");
+        let five = ["Bob", "Carol", "Dave", "Erin", "Frank"];
+        assert_eq!(room(&five, 6).display_name(), "Bob, Carol, Dave, Erin and Frank");
+        assert_eq!(room(&five, 9).display_name(), "Bob, Carol, Dave, Erin, Frank, and 3 others");
+        // no more than five heroes are named
+        let six = ["Bob", "Carol", "Dave", "Erin", "Frank", "Grace"];
+        assert_eq!(room(&six, 7).display_name(), "Bob, Carol, Dave, Erin, Frank, and 1 others");
+        // members without a display name go by their localpart
+        let mut room = room(&["Bob"], 2);
+        room.display_names.clear();
+        assert_eq!(room.display_name(), "bob");
+    }
+
+    #[test]
+    fn empty_room() {
+        // only we are left
+        assert_eq!(room(&["Bob"], 1).display_name(), "Empty Room (was Bob)");
+        assert_eq!(room(&["Bob", "Carol"], 1).display_name(), "Empty Room (was Bob and Carol)");
+        // nobody to name it after
+        assert_eq!(room(&[], 1).display_name(), "!room:localhost");
+    }
+
+    #[test]
+    fn excluding_our_own_user() {
+        // the heroes are the others: we count as a member, not a hero
+        assert_eq!(room(&["Bob"], 2).display_name(), "Bob");
+        let mut invited = room(&["Bob"], 1);
+        invited.invited_member_count = 1;
+        assert_eq!(invited.display_name(), "Bob");
+    }
+
+    #[test]
+    fn find_and_label_after_remove() {
+        let mut rooms = Rooms::default();
+        rooms.load(&["!a:localhost".to_string(), "!b:localhost".to_string(), "!c:localhost".to_string()]);
+        rooms.update_room(&RoomUpdate {
+            room_id: "!c:localhost".to_string(),
+            canonical_alias: Some("#random:localhost".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(rooms.find("3", SERVER_NAME).unwrap().room_id, "!c:localhost");
+        rooms.remove("!b:localhost");
+        // the rooms after it move up, the others keep their number
+        assert_eq!(rooms.find("1", SERVER_NAME).unwrap().room_id, "!a:localhost");
+        assert_eq!(rooms.find("2", SERVER_NAME).unwrap().room_id, "!c:localhost");
+        assert!(rooms.find("3", SERVER_NAME).is_none());
+        assert!(rooms.find("0", SERVER_NAME).is_none());
+        // by alias, with or without '#' and our server name
+        for room in ["random", "#random", "random:localhost", "#random:localhost", "!c:localhost"] {
+            assert_eq!(rooms.find(room, SERVER_NAME).unwrap().room_id, "!c:localhost", "{}", room);
+        }
+        assert!(rooms.find("!b:localhost", SERVER_NAME).is_none());
+        assert_eq!(rooms.label("!c:localhost", SERVER_NAME), "#random");
+        assert_eq!(rooms.label("!b:localhost", SERVER_NAME), "!b:localhost");
+        // joining it again numbers it last
+        rooms.add("!b:localhost");
+        assert_eq!(rooms.find("3", SERVER_NAME).unwrap().room_id, "!b:localhost");
+        assert_eq!(rooms.find("2", SERVER_NAME).unwrap().room_id, "!c:localhost");
+    }
+}
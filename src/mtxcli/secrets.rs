@@ -0,0 +1,341 @@
+//! Secret store
+//!
+//! Keeps the password and tokens encrypted at rest: the secrets are
+//! sealed with XChaCha20-Poly1305 under a key derived from a passphrase
+//! with Argon2id. The store is unlocked once per session, until then
+//! (or if the user declines) secrets are only kept in memory.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Serialize, Deserialize};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::mtxcli::system;
+
+/// The file in the configuration directory (not a settable key)
+pub const SECRETS_FILE: &str = "__secrets";
+
+const STORE_VERSION: u32 = 1;
+/// authenticated (but not encrypted) along with the secrets
+const AAD: &[u8] = b"mtxcli secrets";
+
+const KEY_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+/// Argon2id costs: modest memory as the Precursor only has 16 MiB
+const ARGON2_M_COST: u32 = 4096; // KiB
+const ARGON2_T_COST: u32 = 4;
+const ARGON2_P_COST: u32 = 1;
+
+/// The store as written to disk
+#[derive(Serialize, Deserialize)]
+struct SealedStore {
+    version: u32,
+    /// Argon2id parameters
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    nonce: String,
+    /// the secrets (a JSON object) encrypted and authenticated
+    ciphertext: String,
+}
+
+/// The key and the parameters it was derived with
+struct StoreKey {
+    key: [u8; KEY_LENGTH],
+    salt: Vec<u8>,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+/// Wipe the key from memory
+impl Drop for StoreKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Secrets sealed under a passphrase
+pub struct SecretStore {
+    path: PathBuf,
+    /// set once unlocked (or created)
+    key: Option<StoreKey>,
+    /// the user would rather not unlock the store this session
+    declined: bool,
+    secrets: BTreeMap<String, String>,
+}
+
+/// Never show the key or secrets
+impl fmt::Debug for SecretStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretStore")
+            .field("path", &self.path)
+            .field("unlocked", &self.key.is_some())
+            .field("declined", &self.declined)
+            .field("keys", &self.secrets.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid secret store: {}", msg))
+}
+
+fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    getrandom::getrandom(&mut bytes).expect("couldn't get random data");
+    bytes
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], m_cost: u32, t_cost: u32,
+              p_cost: u32) -> Result<StoreKey, Error> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LENGTH))
+        .map_err(|e| invalid(&e.to_string()))?;
+    // derived in place so that no copy of the key is left behind
+    let mut key = StoreKey { key: [0u8; KEY_LENGTH], salt: salt.to_vec(), m_cost, t_cost, p_cost };
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key.key)
+        .map_err(|e| invalid(&e.to_string()))?;
+    Ok(key)
+}
+
+impl SecretStore {
+    /// The (locked) store in the configuration directory
+    pub fn new(config_dir: &Path) -> Self {
+        SecretStore {
+            path: config_dir.join(SECRETS_FILE),
+            key: None,
+            declined: false,
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    /// Is there a store on disk (to unlock)?
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    /// Are secrets written to disk?
+    pub fn is_unlocked(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Should we ask the user for the passphrase?
+    pub fn needs_unlock(&self) -> bool {
+        self.key.is_none() && ! self.declined
+    }
+
+    /// Keep secrets in memory only (for this session)
+    pub fn decline(&mut self) {
+        self.declined = true;
+    }
+
+    /// Unlock the store on disk with the passphrase (PermissionDenied if
+    /// wrong), secrets set in the meantime are kept and saved
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), Error> {
+        let mut contents = String::new();
+        File::open(&self.path)?.read_to_string(&mut contents)?;
+        let sealed: SealedStore = ureq::serde_json::from_str(&contents)
+            .map_err(|e| invalid(&e.to_string()))?;
+        if sealed.version != STORE_VERSION {
+            return Err(invalid(&format!("unsupported version {}", sealed.version)));
+        }
+        let salt = base64::decode(&sealed.salt).map_err(|e| invalid(&e.to_string()))?;
+        let nonce = base64::decode(&sealed.nonce).map_err(|e| invalid(&e.to_string()))?;
+        let ciphertext = base64::decode(&sealed.ciphertext).map_err(|e| invalid(&e.to_string()))?;
+        if nonce.len() != NONCE_LENGTH {
+            return Err(invalid("bad nonce"));
+        }
+        let key = derive_key(passphrase, &salt, sealed.m_cost, sealed.t_cost, sealed.p_cost)?;
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key.key));
+        let plaintext = cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: AAD })
+            .map(Zeroizing::new)
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "wrong passphrase"))?;
+        let secrets: BTreeMap<String, String> = ureq::serde_json::from_slice(&plaintext)
+            .map_err(|e| invalid(&e.to_string()))?;
+        for (name, value) in secrets {
+            self.secrets.entry(name).or_insert(value);
+        }
+        self.key = Some(key);
+        self.declined = false;
+        self.save()
+    }
+
+    /// Create a new store sealed with the passphrase
+    pub fn create(&mut self, passphrase: &str) -> Result<(), Error> {
+        let salt = random_bytes(SALT_LENGTH);
+        self.key = Some(derive_key(passphrase, &salt, ARGON2_M_COST, ARGON2_T_COST, ARGON2_P_COST)?);
+        self.declined = false;
+        self.save()
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.secrets.get(name).cloned()
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        self.secrets.insert(name.to_string(), value.to_string());
+        self.save()
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), Error> {
+        if self.secrets.remove(name).is_some() {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Seal the secrets (with a fresh nonce) and write the store
+    fn save(&self) -> Result<(), Error> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
+                return Ok(());
+            }
+        };
+        let plaintext = ureq::serde_json::to_vec(&self.secrets)
+            .map(Zeroizing::new)
+            .map_err(|e| Error::other(e.to_string()))?;
+        let nonce = random_bytes(NONCE_LENGTH);
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key.key));
+        let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: &plaintext, aad: AAD })
+            .map_err(|_| Error::other("cannot encrypt the secrets"))?;
+        let sealed = SealedStore {
+            version: STORE_VERSION,
+            salt: base64::encode(&key.salt),
+            m_cost: key.m_cost,
+            t_cost: key.t_cost,
+            p_cost: key.p_cost,
+            nonce: base64::encode(&nonce),
+            ciphertext: base64::encode(&ciphertext),
+        };
+        let contents = ureq::serde_json::to_string(&sealed)
+            .map_err(|e| Error::other(e.to_string()))?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // write then rename so that a crash cannot lose the store, only
+        // the owner may read it (even though it is encrypted)
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        system::owner_rw(file.try_clone()?);
+        file.write_all(contents.as_bytes())?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use ureq::serde_json::{self, Value};
+
+    use super::*;
+
+    fn config_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mtxcli-secrets-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn created(dir: &Path) -> SecretStore {
+        let mut store = SecretStore::new(dir);
+        assert!(! store.exists());
+        store.create("passphrase").unwrap();
+        store.set("password", "secret").unwrap();
+        store.set("_token", "token1").unwrap();
+        store
+    }
+
+    // flip a bit of one of the base64 fields of the store on disk
+    fn tamper(dir: &Path, field: &str) {
+        let path = dir.join(SECRETS_FILE);
+        let mut sealed: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let mut bytes = base64::decode(sealed[field].as_str().unwrap()).unwrap();
+        bytes[0] ^= 1;
+        sealed[field] = Value::String(base64::encode(&bytes));
+        fs::write(&path, serde_json::to_string(&sealed).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn create_and_unlock() {
+        let dir = config_dir("unlock");
+        created(&dir);
+        let mut store = SecretStore::new(&dir);
+        assert!(store.exists());
+        assert!(store.needs_unlock());
+        assert_eq!(store.get("password"), None);
+        store.unlock("passphrase").unwrap();
+        assert!(store.is_unlocked());
+        assert_eq!(store.get("password").as_deref(), Some("secret"));
+        assert_eq!(store.get("_token").as_deref(), Some("token1"));
+        // the secrets are not in the file in plaintext
+        let contents = fs::read_to_string(dir.join(SECRETS_FILE)).unwrap();
+        assert!(! contents.contains("secret") && ! contents.contains("token1"));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let dir = config_dir("wrong");
+        created(&dir);
+        let before = fs::read(dir.join(SECRETS_FILE)).unwrap();
+        let mut store = SecretStore::new(&dir);
+        let e = store.unlock("wrong").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(! store.is_unlocked());
+        // secrets set while locked stay in memory
+        store.set("password", "other").unwrap();
+        assert_eq!(fs::read(dir.join(SECRETS_FILE)).unwrap(), before);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        let dir = config_dir("ciphertext");
+        created(&dir);
+        tamper(&dir, "ciphertext");
+        let e = SecretStore::new(&dir).unlock("passphrase").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn tampered_nonce() {
+        let dir = config_dir("nonce");
+        created(&dir);
+        tamper(&dir, "nonce");
+        assert!(SecretStore::new(&dir).unlock("passphrase").is_err());
+        // a truncated nonce
+        let path = dir.join(SECRETS_FILE);
+        let mut sealed: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        sealed["nonce"] = Value::String(base64::encode([0u8; 12]));
+        fs::write(&path, serde_json::to_string(&sealed).unwrap()).unwrap();
+        let e = SecretStore::new(&dir).unlock("passphrase").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn remove_persists() {
+        let dir = config_dir("remove");
+        let mut store = created(&dir);
+        store.remove("_token").unwrap();
+        let mut store = SecretStore::new(&dir);
+        store.unlock("passphrase").unwrap();
+        assert_eq!(store.get("_token"), None);
+        assert_eq!(store.get("password").as_deref(), Some("secret"));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,200 @@
+//! Single sign-on
+//!
+//! Listens on the loopback interface for the browser to be redirected
+//! back from the homeserver's SSO page with the `loginToken`.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::mtxcli::error::MatrixError;
+use crate::mtxcli::url;
+
+/// How long we wait for the user to log in with the browser
+pub const SSO_TIMEOUT: u64 = 300000; // ms
+/// How often we check for the browser to connect
+const SSO_POLL: u64 = 100; // ms
+
+const LOGIN_TOKEN: &str = "loginToken";
+
+const SSO_DONE: &str = "<html><body><p>mtxcli is now logged in: you may close this window.</p></body></html>";
+const SSO_MISSING: &str = "<html><body><p>mtxcli did not receive a login token.</p></body></html>";
+
+/// Loopback listener for the SSO redirect
+#[derive(Debug)]
+pub struct SsoListener {
+    listener: TcpListener,
+}
+
+impl SsoListener {
+    /// Listen on a free port on the loopback interface
+    pub fn bind() -> Result<Self, MatrixError> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                Ok(listener)
+            })
+            .map_err(|e| MatrixError::Transport(format!("cannot listen for SSO redirect: {}", e)))?;
+        Ok(SsoListener { listener })
+    }
+
+    /// The URL the homeserver should redirect the browser to
+    pub fn redirect_url(&self) -> Result<String, MatrixError> {
+        let addr = self.listener.local_addr()
+            .map_err(|e| MatrixError::Transport(format!("cannot listen for SSO redirect: {}", e)))?;
+        Ok(format!("http://{}/", addr))
+    }
+
+    /// Wait for the browser to bring us the login token
+    pub fn wait_for_token(&self, timeout: Duration) -> Result<String, MatrixError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Some(login_token) = read_redirect(stream) {
+                        return Ok(login_token);
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(MatrixError::Transport("timed out waiting for SSO login".to_string()));
+                    }
+                    thread::sleep(Duration::from_millis(SSO_POLL));
+                },
+                Err(e) => {
+                    return Err(MatrixError::Transport(format!("SSO redirect failed: {}", e)));
+                }
+            }
+        }
+    }
+}
+
+/// Returns the login token from the request target (if present), only
+/// the redirect_url path (/) is accepted
+fn login_token(target: &str) -> Option<String> {
+    let (path, query) = target.split_once('?')?;
+    if path != "/" {
+        return None;
+    }
+    query.split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == LOGIN_TOKEN)
+        .map(|(_, value)| url::decode(value))
+        .filter(|value| value.len() > 0)
+}
+
+/// Answer the browser (and close the connection)
+fn respond(mut stream: &TcpStream, status: &str, body: &str) {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           status, body.len(), body);
+    stream.write_all(response.as_bytes()).ok();
+}
+
+/// Read one request from the browser, answer it and return the login token
+fn read_redirect(stream: TcpStream) -> Option<String> {
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(Duration::from_millis(SSO_POLL * 50))).ok()?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let target = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [_, target, version] if version.starts_with("HTTP/") => target,
+        _ => {
+            respond(&stream, "400 Bad Request", SSO_MISSING);
+            return None;
+        }
+    };
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line).ok()? > 2 {
+        line.clear();
+    }
+    let login_token = login_token(target);
+    match login_token {
+        Some(_) => respond(&stream, "200 OK", SSO_DONE),
+        None => respond(&stream, "404 Not Found", SSO_MISSING),
+    }
+    login_token
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    // send the request to read_redirect, returns the token and the response
+    fn redirect(request: &str) -> (Option<String>, String) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut browser = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        browser.write_all(request.as_bytes()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let login_token = read_redirect(stream);
+        let mut response = String::new();
+        browser.read_to_string(&mut response).unwrap();
+        (login_token, response)
+    }
+
+    #[test]
+    fn login_tokens() {
+        assert_eq!(login_token("/?loginToken=abc").as_deref(), Some("abc"));
+        assert_eq!(login_token("/?state=1&loginToken=a%2Bb%2Fc%3D%3D&x").as_deref(), Some("a+b/c=="));
+        assert_eq!(login_token("/?loginToken="), None);
+        assert_eq!(login_token("/?logintoken=abc"), None);
+        assert_eq!(login_token("/"), None);
+        assert_eq!(login_token(""), None);
+        // only the redirect_url
+        assert_eq!(login_token("/favicon.ico?loginToken=abc"), None);
+        assert_eq!(login_token("/other/?loginToken=abc"), None);
+    }
+
+    #[test]
+    fn redirect_with_token() {
+        let (login_token, response) = redirect("GET /?loginToken=abc%2Bdef HTTP/1.1\r\n\
+                                                Host: 127.0.0.1\r\nAccept: text/html\r\n\r\n");
+        assert_eq!(login_token.as_deref(), Some("abc+def"));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with(SSO_DONE));
+    }
+
+    #[test]
+    fn redirect_without_token() {
+        for request in ["GET / HTTP/1.1\r\n\r\n",
+                        "GET /favicon.ico HTTP/1.1\r\n\r\n",
+                        "GET /favicon.ico?loginToken=abc HTTP/1.1\r\n\r\n"] {
+            let (login_token, response) = redirect(request);
+            assert_eq!(login_token, None, "{:?}", request);
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{:?}: {}", request, response);
+        }
+    }
+
+    #[test]
+    fn malformed_request() {
+        for request in ["GARBAGE\r\n\r\n",
+                        "GET /?loginToken=abc\r\n\r\n",
+                        "GET /?loginToken=abc SMTP/1.0\r\n\r\n",
+                        "\r\n"] {
+            let (login_token, response) = redirect(request);
+            assert_eq!(login_token, None, "{:?}", request);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", request, response);
+        }
+    }
+
+    #[test]
+    fn wait_for_the_browser() {
+        let listener = SsoListener::bind().unwrap();
+        let address = listener.listener.local_addr().unwrap();
+        assert_eq!(listener.redirect_url().unwrap(), format!("http://{}/", address));
+        let browser = thread::spawn(move || {
+            for request in ["GET /favicon.ico HTTP/1.1\r\n\r\n", "GET /?loginToken=abc HTTP/1.1\r\n\r\n"] {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+                stream.read_to_string(&mut String::new()).unwrap();
+            }
+        });
+        assert_eq!(listener.wait_for_token(Duration::from_secs(10)).unwrap(), "abc");
+        browser.join().unwrap();
+        assert!(listener.wait_for_token(Duration::from_millis(0)).is_err());
+    }
+}
@@ -88,7 +88,7 @@ const OTHER_DIR_RW: u32 = 0o077;
 #[cfg(not(unix))]
 /// Sets the file permissions to read and write for the owner (only)
 #[allow(dead_code)]
-fn owner_rw(_: File) {
+pub fn owner_rw(_: File) {
 }
 
 #[cfg(not(unix))]
@@ -100,7 +100,7 @@ fn path_owner_rw<P: AsRef<Path>>(_path: P) {
 #[cfg(unix)]
 /// Sets the file permissions to read and write for the owner (only)
 #[allow(dead_code)]
-fn owner_rw(file: File) {
+pub fn owner_rw(file: File) {
     let metadata = file.metadata()
         .expect("unable to get file permissions");
     let file_type = metadata.file_type();
@@ -0,0 +1,300 @@
+//! TLS configuration
+//!
+//! Builds the rustls configuration for a custom CA bundle and/or a
+//! pinned server public key (SHA-256 of the SubjectPublicKeyInfo).
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+
+use crate::mtxcli::error::MatrixError;
+
+/// Optional prefix for pins (as used by curl --pinnedpubkey)
+const PIN_PREFIX: &str = "sha256//";
+
+/// DER tags used to find the SubjectPublicKeyInfo
+const DER_SEQUENCE: u8 = 0x30;
+const DER_VERSION: u8 = 0xa0;
+
+/// Verifies the certificate chain as usual, then checks the pin
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pin: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate],
+                          server_name: &ServerName, scts: &mut dyn Iterator<Item = &[u8]>,
+                          ocsp_response: &[u8], now: SystemTime)
+                          -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name,
+                                                     scts, ocsp_response, now)?;
+        match spki(&end_entity.0) {
+            Some(spki) => {
+                let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+                if digest.as_ref() == self.pin.as_slice() {
+                    Ok(verified)
+                } else {
+                    error!("certificate pin mismatch: server has {}{}",
+                           PIN_PREFIX, base64::encode(digest.as_ref()));
+                    Err(rustls::Error::General("certificate does not match pin".to_string()))
+                }
+            },
+            None => {
+                Err(rustls::Error::InvalidCertificateEncoding)
+            }
+        }
+    }
+}
+
+/// A DER element (and what follows it)
+struct Der<'a> {
+    tag: u8,
+    /// the whole element: tag, length and content
+    element: &'a [u8],
+    content: &'a [u8],
+    rest: &'a [u8],
+}
+
+/// Returns the DER element at the start of der
+fn der_element(der: &[u8]) -> Option<Der<'_>> {
+    let tag = *der.first()?;
+    let first = *der.get(1)? as usize;
+    let (length, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let mut length = 0usize;
+        for i in 0..n {
+            length = (length << 8) | *der.get(2 + i)? as usize;
+        }
+        (length, 2 + n)
+    };
+    let end = header.checked_add(length)?;
+    if end > der.len() {
+        return None;
+    }
+    Some(Der {
+        tag,
+        element: &der[..end],
+        content: &der[header..end],
+        rest: &der[end..],
+    })
+}
+
+/// Returns the SubjectPublicKeyInfo of the certificate (DER)
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let certificate = der_element(cert)?;
+    if certificate.tag != DER_SEQUENCE {
+        return None;
+    }
+    let tbs = der_element(certificate.content)?;
+    if tbs.tag != DER_SEQUENCE {
+        return None;
+    }
+    let mut rest = tbs.content;
+    let first = der_element(rest)?;
+    if first.tag == DER_VERSION {
+        rest = first.rest;
+    }
+    // serial, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.rest;
+    }
+    let spki = der_element(rest)?;
+    if spki.tag == DER_SEQUENCE {
+        Some(spki.element)
+    } else {
+        None
+    }
+}
+
+/// Decodes the pin: base64 of the SHA-256 (optionally prefixed with sha256//)
+fn decode_pin(pin: &str) -> Result<Vec<u8>, MatrixError> {
+    let pin = pin.trim();
+    let pin = pin.strip_prefix(PIN_PREFIX).unwrap_or(pin);
+    match base64::decode(pin) {
+        Ok(digest) if digest.len() == ring::digest::SHA256_OUTPUT_LEN => Ok(digest),
+        _ => Err(MatrixError::Config(format!("invalid pin (expected base64 SHA-256): {}", pin)))
+    }
+}
+
+/// Returns the trusted roots: the CA bundle (if given) else the built in roots
+fn root_store(ca_file: Option<&str>) -> Result<RootCertStore, MatrixError> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => {
+            let file = File::open(ca_file)
+                .map_err(|e| MatrixError::Config(format!("cannot open ca_file {}: {}", ca_file, e)))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .map_err(|e| MatrixError::Config(format!("cannot read ca_file {}: {}", ca_file, e)))?;
+            for cert in certs.iter() {
+                roots.add(&Certificate(cert.to_vec()))
+                    .map_err(|e| MatrixError::Config(format!("invalid certificate in {}: {}", ca_file, e)))?;
+            }
+            if roots.is_empty() {
+                return Err(MatrixError::Config(format!("no certificates in ca_file {}", ca_file)));
+            }
+        },
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+    }
+    Ok(roots)
+}
+
+/// Returns the TLS configuration for the CA bundle and pin
+pub fn client_config(ca_file: Option<&str>, pin: Option<&str>)
+                     -> Result<Arc<ClientConfig>, MatrixError> {
+    let roots = root_store(ca_file)?;
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match pin {
+        Some(pin) => {
+            let verifier = PinnedVerifier {
+                inner: WebPkiVerifier::new(roots, None),
+                pin: decode_pin(pin)?,
+            };
+            builder.with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        },
+        None => {
+            builder.with_root_certificates(roots)
+                .with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    /// Self signed test CA (P-256)
+    const CA_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBmDCCAT+gAwIBAgIUCfrF0snUYtlA52xb7kTl503/RnUwCgYIKoZIzj0EAwIw
+GTEXMBUGA1UEAwwObXR4Y2xpIHRlc3QgQ0EwIBcNMjYxMDE4MDc1NzUzWhgPMjEy
+NjA5MjQwNzU3NTNaMBkxFzAVBgNVBAMMDm10eGNsaSB0ZXN0IENBMFkwEwYHKoZI
+zj0CAQYIKoZIzj0DAQcDQgAEhNYm1VeTu9x95aoAhAtbQyNWxI0lRi87MT27V/rb
+a1JjJKzX/3RR5PQ9kWeDWVaoZYTHZYX3neYVM0QoqV1GIaNjMGEwHQYDVR0OBBYE
+FJC9l3TuyE2QL/nvf7VchqvArEjRMB8GA1UdIwQYMBaAFJC9l3TuyE2QL/nvf7Vc
+hqvArEjRMA8GA1UdEwEB/wQFMAMBAf8wDgYDVR0PAQH/BAQDAgIEMAoGCCqGSM49
+BAMCA0cAMEQCIA6hh8ik5N2xg7e9og/jnDZ7FtBkOeT8GePcREfYNH2nAiAXalzr
+IjKipCRcri49N9nYNNpAqei7m2W+u8Vj54ZnZA==
+-----END CERTIFICATE-----
+";
+
+    /// Certificate for localhost issued by the test CA
+    const LOCALHOST_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBqjCCAU+gAwIBAgIUPq/UazPKC6wke5sjlLdyyExP0XAwCgYIKoZIzj0EAwIw
+GTEXMBUGA1UEAwwObXR4Y2xpIHRlc3QgQ0EwIBcNMjYxMDE4MDc1NzUzWhgPMjEy
+NjA5MjQwNzU3NTNaMBQxEjAQBgNVBAMMCWxvY2FsaG9zdDBZMBMGByqGSM49AgEG
+CCqGSM49AwEHA0IABH6VzmJXgUpSZrCRjGvGlH4+he2fSEZmNpZyeEQTKY0AmFxJ
+2/7JTMmkGwHwW3HMjZ9zvbkIO/W4gRHQmZGiXQOjeDB2MBQGA1UdEQQNMAuCCWxv
+Y2FsaG9zdDAJBgNVHRMEAjAAMBMGA1UdJQQMMAoGCCsGAQUFBwMBMB0GA1UdDgQW
+BBSCPZj5kpksCz2wvOcIF7LW9+IBwzAfBgNVHSMEGDAWgBSQvZd07shNkC/573+1
+XIarwKxI0TAKBggqhkjOPQQDAgNJADBGAiEA4K+yIpnQXLx3TKfJTYPDw078duAF
+kjl6DGbo2ldq5FcCIQC3R0eXM2zCBeQJoFcDmg1MSpYHTEFLDAKMpaGzxKZS8w==
+-----END CERTIFICATE-----
+";
+
+    /// openssl x509 -pubkey -noout | openssl pkey -pubin -outform der
+    ///   | openssl dgst -sha256 -binary | base64
+    const LOCALHOST_PIN: &str = "t8ajIzEIklC/cus86b3bDa0mcYQjtp3/Ld/HIMu99JM=";
+
+    fn der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0)
+    }
+
+    fn verify(pin: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(der(CA_PEM))).unwrap();
+        let verifier = PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pin: decode_pin(pin).unwrap(),
+        };
+        // both certificates are valid from 2026 to 2126
+        let now = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+        verifier.verify_server_cert(&Certificate(der(LOCALHOST_PEM)), &[],
+                                    &ServerName::try_from("localhost").unwrap(),
+                                    &mut std::iter::empty(), &[], now)
+    }
+
+    #[test]
+    fn spki_digest_matches_openssl() {
+        let cert = der(LOCALHOST_PEM);
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki(&cert).unwrap());
+        assert_eq!(base64::encode(digest.as_ref()), LOCALHOST_PIN);
+    }
+
+    #[test]
+    fn pinned_certificate() {
+        assert!(verify(LOCALHOST_PIN).is_ok());
+        assert!(verify(&format!("{}{}", PIN_PREFIX, LOCALHOST_PIN)).is_ok());
+    }
+
+    #[test]
+    fn pin_mismatch() {
+        // the CA's key is trusted, but it is not the server's key
+        let cert = der(CA_PEM);
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki(&cert).unwrap());
+        assert!(verify(&base64::encode(digest.as_ref())).is_err());
+        assert!(verify(&base64::encode([0u8; 32])).is_err());
+    }
+
+    #[test]
+    fn decode_pins() {
+        let digest = base64::decode(LOCALHOST_PIN).unwrap();
+        assert_eq!(decode_pin(LOCALHOST_PIN).unwrap(), digest);
+        assert_eq!(decode_pin(&format!(" sha256//{}\n", LOCALHOST_PIN)).unwrap(), digest);
+        assert!(decode_pin("not base64!").is_err());
+        assert!(decode_pin("").is_err());
+        // SHA-1 sized
+        assert!(decode_pin(&base64::encode([0u8; 20])).is_err());
+        assert!(decode_pin(&format!("sha1//{}", LOCALHOST_PIN)).is_err());
+    }
+
+    #[test]
+    fn truncated_der() {
+        let cert = der(LOCALHOST_PEM);
+        for n in 0..cert.len() {
+            assert!(spki(&cert[..n]).is_none(), "truncated to {} bytes", n);
+        }
+    }
+
+    #[test]
+    fn malformed_der() {
+        // not a SEQUENCE
+        assert!(spki(&[0x02, 0x01, 0x00]).is_none());
+        // indefinite length
+        assert!(spki(&[DER_SEQUENCE, 0x80, 0x00, 0x00]).is_none());
+        // length longer than 4 bytes
+        assert!(spki(&[DER_SEQUENCE, 0x85, 0xff, 0xff, 0xff, 0xff, 0xff]).is_none());
+        // length past the end
+        assert!(spki(&[DER_SEQUENCE, 0x84, 0xff, 0xff, 0xff, 0xff]).is_none());
+        // an empty TBSCertificate
+        assert!(spki(&[DER_SEQUENCE, 0x02, DER_SEQUENCE, 0x00]).is_none());
+        // the subject is followed by an INTEGER, not the SubjectPublicKeyInfo
+        let mut tbs = Vec::new();
+        for _ in 0..6 {
+            tbs.extend_from_slice(&[0x02, 0x01, 0x00]);
+        }
+        let mut cert = vec![DER_SEQUENCE, tbs.len() as u8 + 2, DER_SEQUENCE, tbs.len() as u8];
+        cert.extend_from_slice(&tbs);
+        assert!(spki(&cert).is_none());
+    }
+}
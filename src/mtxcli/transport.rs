@@ -0,0 +1,122 @@
+//! Matrix transport
+//!
+//! Carries requests to the homeserver and returns the JSON response.
+//! The real transport is `web::Http` (using ureq), while the
+//! `scripted::ScriptedTransport` answers from memory (for the unit tests).
+
+use std::fmt;
+
+use ureq::serde_json::Value;
+
+use crate::mtxcli::error::MatrixError;
+use crate::mtxcli::web::HttpConfig;
+
+#[cfg(test)]
+pub mod scripted;
+
+/// HTTP method of a Request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+        }
+    }
+}
+
+/// A request for the homeserver
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    pub url: String,
+    /// access token (if authenticated)
+    pub token: Option<String>,
+    /// JSON request body
+    pub body: Option<String>,
+    /// the body is a form (application/x-www-form-urlencoded), not JSON
+    pub form: bool,
+    /// the server may hold this request (e.g. /sync)
+    pub long_poll: bool,
+    /// may be sent again even if the server may have handled it
+    /// (e.g. GET, or PUT with a transaction id)
+    pub idempotent: bool,
+}
+
+impl Request {
+    pub fn new(method: Method, url: &str) -> Self {
+        Request {
+            method,
+            url: url.to_string(),
+            token: None,
+            body: None,
+            form: false,
+            long_poll: false,
+            idempotent: method != Method::Post,
+        }
+    }
+}
+
+/// Carries requests to a Matrix homeserver
+pub trait MatrixTransport: fmt::Debug {
+    /// Send the request and return the JSON response
+    fn request(&self, request: &Request) -> Result<Value, MatrixError>;
+
+    /// Apply a new configuration (if relevant for this transport)
+    fn configure(&mut self, _config: &HttpConfig) -> Result<(), MatrixError> {
+        Ok(())
+    }
+
+    fn get_json(&self, url: &str) -> Result<Value, MatrixError> {
+        self.request(&Request::new(Method::Get, url))
+    }
+
+    fn get_json_auth(&self, url: &str, token: &str) -> Result<Value, MatrixError> {
+        let mut request = Request::new(Method::Get, url);
+        request.token = Some(token.to_string());
+        self.request(&request)
+    }
+
+    /// GET for a long poll (the server may hold the request)
+    fn get_json_auth_poll(&self, url: &str, token: &str) -> Result<Value, MatrixError> {
+        let mut request = Request::new(Method::Get, url);
+        request.token = Some(token.to_string());
+        request.long_poll = true;
+        self.request(&request)
+    }
+
+    fn post_string(&self, url: &str, request_body: &str) -> Result<Value, MatrixError> {
+        let mut request = Request::new(Method::Post, url);
+        request.body = Some(request_body.to_string());
+        self.request(&request)
+    }
+
+    /// POST a form (e.g. to an OAuth 2.0 endpoint)
+    fn post_form(&self, url: &str, form: &str) -> Result<Value, MatrixError> {
+        let mut request = Request::new(Method::Post, url);
+        request.body = Some(form.to_string());
+        request.form = true;
+        self.request(&request)
+    }
+
+    fn post_string_auth(&self, url: &str, request_body: &str, token: &str) -> Result<Value, MatrixError> {
+        let mut request = Request::new(Method::Post, url);
+        request.token = Some(token.to_string());
+        request.body = Some(request_body.to_string());
+        self.request(&request)
+    }
+
+    fn put_string_auth(&self, url: &str, request_body: &str, token: &str) -> Result<Value, MatrixError> {
+        let mut request = Request::new(Method::Put, url);
+        request.token = Some(token.to_string());
+        request.body = Some(request_body.to_string());
+        self.request(&request)
+    }
+}
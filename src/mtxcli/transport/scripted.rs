@@ -0,0 +1,75 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ureq::serde_json::Value;
+
+use crate::mtxcli::error::MatrixError;
+use crate::mtxcli::transport::{MatrixTransport, Method, Request};
+
+/// A scripted response: answers the first matching request
+#[derive(Debug)]
+struct Script {
+    method: Method,
+    path: String,
+    response: Result<Value, MatrixError>,
+}
+
+/// In-memory transport which answers with scripted responses
+///
+/// Each response is used once, for the first request with the same
+/// method whose URL contains the scripted path. All requests are
+/// recorded so that they may be inspected afterwards (clones share the
+/// responses and requests, so keep one to inspect a boxed transport).
+#[derive(Debug, Default, Clone)]
+pub struct ScriptedTransport {
+    scripts: Rc<RefCell<VecDeque<Script>>>,
+    requests: Rc<RefCell<Vec<Request>>>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> Self {
+        ScriptedTransport::default()
+    }
+
+    /// Script the response for the next method request matching path
+    pub fn respond(&self, method: Method, path: &str,
+                   response: Result<Value, MatrixError>) -> &Self {
+        self.scripts.borrow_mut().push_back(Script {
+            method,
+            path: path.to_string(),
+            response,
+        });
+        self
+    }
+
+    /// Returns all requests received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.borrow().clone()
+    }
+
+    /// Returns the number of scripted responses not yet used
+    pub fn remaining(&self) -> usize {
+        self.scripts.borrow().len()
+    }
+}
+
+impl MatrixTransport for ScriptedTransport {
+    fn request(&self, request: &Request) -> Result<Value, MatrixError> {
+        debug!("scripted {} {}", request.method.as_str(), request.url);
+        self.requests.borrow_mut().push(request.clone());
+        let mut scripts = self.scripts.borrow_mut();
+        let found = scripts.iter()
+            .position(|script| script.method == request.method
+                      && request.url.contains(&script.path));
+        match found {
+            Some(index) => {
+                scripts.remove(index).unwrap().response
+            },
+            None => {
+                Err(MatrixError::Transport(format!("no scripted response for {} {}",
+                                                   request.method.as_str(), request.url)))
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! User-interactive authentication
+//!
+//! Endpoints such as /register, /delete_devices or /account/password
+//! answer 401 with a challenge: the flows of stages the server accepts
+//! and the session to continue. The driver picks a flow we can
+//! complete, asks the user for whatever the next stage needs and
+//! resubmits the request with the `auth` dict until it succeeds.
+
+use serde::Deserialize;
+use ureq::serde_json::{json, Value};
+
+use crate::mtxcli::error::MatrixError;
+use crate::mtxcli::web;
+
+pub const MTX_LOGIN_DUMMY: &str = "m.login.dummy";
+pub const MTX_LOGIN_REGISTRATION_TOKEN: &str = "m.login.registration_token";
+/// the registration token stage before Matrix v1.2 (MSC3231)
+pub const MTX_LOGIN_REGISTRATION_TOKEN_UNSTABLE: &str = "org.matrix.msc3231.login.registration_token";
+pub const MTX_LOGIN_TERMS: &str = "m.login.terms";
+
+/// The stages we know how to complete
+const SUPPORTED_STAGES: &[&str] = &[
+    web::MTX_LOGIN_PASSWORD,
+    MTX_LOGIN_DUMMY,
+    MTX_LOGIN_REGISTRATION_TOKEN,
+    MTX_LOGIN_REGISTRATION_TOKEN_UNSTABLE,
+    MTX_LOGIN_TERMS,
+];
+
+/// Talks to the user on behalf of the driver
+pub trait Prompt {
+    /// Show a line of text
+    fn tell(&self, text: &str);
+    /// Ask a question, returns the answer (None at end of input)
+    fn ask(&self, question: &str) -> Option<String>;
+    /// Ask for a secret (which should not be echoed)
+    fn ask_secret(&self, question: &str) -> Option<String> {
+        self.ask(question)
+    }
+}
+
+/// Who is authenticating (for the m.login.password stage)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub user: String,
+    /// asked for when empty
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Flow {
+    #[serde(default)]
+    pub stages: Vec<String>,
+}
+
+/// The 401 challenge of user-interactive authentication
+#[derive(Debug, Clone, Deserialize)]
+pub struct Challenge {
+    pub session: Option<String>,
+    #[serde(default)]
+    pub flows: Vec<Flow>,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub completed: Vec<String>,
+    /// set when the stage we submitted failed
+    pub errcode: Option<String>,
+}
+
+impl Challenge {
+    /// Returns the challenge if the error is one
+    pub fn from_error(e: &MatrixError) -> Option<Result<Self, MatrixError>> {
+        e.interactive_auth().map(|info| {
+            ureq::serde_json::from_value(info.clone())
+                .map_err(|e| MatrixError::InvalidResponse(format!("invalid authentication challenge: {}", e)))
+        })
+    }
+
+    fn is_completed(&self, stage: &str) -> bool {
+        self.completed.iter().any(|completed| completed == stage)
+    }
+
+    /// Returns the next stage of the first flow we can complete
+    pub fn next_stage(&self) -> Result<&str, MatrixError> {
+        let flow = self.flows.iter()
+            .find(|flow| flow.stages.iter().all(|stage| SUPPORTED_STAGES.contains(&stage.as_str())))
+            .ok_or_else(|| {
+                let flows: Vec<&Vec<String>> = self.flows.iter().map(|flow| &flow.stages).collect();
+                MatrixError::Config(format!("authentication requires unsupported stages: {:?}", flows))
+            })?;
+        flow.stages.iter()
+            .find(|stage| ! self.is_completed(stage))
+            .map(|stage| stage.as_str())
+            .ok_or_else(|| MatrixError::InvalidResponse("no authentication stage left".to_string()))
+    }
+
+    /// Returns the (name, url) of the policies to accept for m.login.terms
+    pub fn policies(&self) -> Vec<(String, String)> {
+        let policies = self.params.pointer(&format!("/{}/policies", MTX_LOGIN_TERMS))
+            .and_then(Value::as_object);
+        policies.into_iter().flatten()
+            .map(|(name, policy)| {
+                // prefer the English version of the policy
+                let translation = policy.get("en")
+                    .or_else(|| policy.as_object()
+                             .and_then(|policy| policy.values().find(|value| value.is_object())));
+                let title = translation.and_then(|t| t.get("name")).and_then(Value::as_str).unwrap_or(name);
+                let url = translation.and_then(|t| t.get("url")).and_then(Value::as_str).unwrap_or_default();
+                (title.to_string(), url.to_string())
+            })
+            .collect()
+    }
+}
+
+/// Returns the auth dict answering the stage (asking the user as needed)
+fn answer(prompt: &dyn Prompt, credentials: &Credentials, challenge: &Challenge,
+          stage: &str) -> Result<Value, MatrixError> {
+    let mut auth = json!({ "type": stage });
+    if let Some(session) = &challenge.session {
+        auth["session"] = json!(session);
+    }
+    match stage {
+        web::MTX_LOGIN_PASSWORD => {
+            let password = if credentials.password.len() > 0 {
+                credentials.password.clone()
+            } else {
+                prompt.ask_secret("password:").unwrap_or_default()
+            };
+            if password.len() == 0 {
+                return Err(MatrixError::Config("a password is required".to_string()));
+            }
+            auth["identifier"] = json!({ "type": web::MTX_ID_USER, "user": credentials.user });
+            auth["password"] = json!(password);
+        },
+        MTX_LOGIN_REGISTRATION_TOKEN | MTX_LOGIN_REGISTRATION_TOKEN_UNSTABLE => {
+            match prompt.ask("registration token:") {
+                Some(token) if token.len() > 0 => {
+                    auth["token"] = json!(token);
+                },
+                _ => {
+                    return Err(MatrixError::Config("a registration token is required".to_string()));
+                }
+            }
+        },
+        MTX_LOGIN_TERMS => {
+            for (name, url) in challenge.policies() {
+                prompt.tell(&format!("{}: {}", name, url));
+            }
+            let accepted = prompt.ask("do you accept the terms? [y/N]").unwrap_or_default();
+            if ! matches!(accepted.to_lowercase().as_str(), "y" | "yes") {
+                return Err(MatrixError::Config("the terms were not accepted".to_string()));
+            }
+        },
+        _ => { } // m.login.dummy
+    }
+    Ok(auth)
+}
+
+/// Send the request (without auth first), completing the stages of
+/// user-interactive authentication until it succeeds or a stage fails
+pub fn authenticate<T, F>(prompt: &dyn Prompt, credentials: &Credentials,
+                          mut request: F) -> Result<T, MatrixError>
+where F: FnMut(Option<&Value>) -> Result<T, MatrixError> {
+    let mut auth: Option<Value> = None;
+    loop {
+        let e = match request(auth.as_ref()) {
+            Ok(response) => {
+                return Ok(response);
+            },
+            Err(e) => e
+        };
+        let challenge = match Challenge::from_error(&e) {
+            Some(challenge) => challenge?,
+            None => {
+                return Err(e);
+            }
+        };
+        if let Some(auth) = &auth {
+            // e.g. a wrong password or registration token
+            let stage = auth.get("type").and_then(Value::as_str).unwrap_or_default();
+            if challenge.errcode.is_some() || ! challenge.is_completed(stage) {
+                return Err(e);
+            }
+        }
+        let stage = challenge.next_stage()?;
+        debug!("authentication stage {}", stage);
+        auth = Some(answer(prompt, credentials, &challenge, stage)?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::mtxcli::transport::{Method, Request};
+    use crate::mtxcli::transport::scripted::ScriptedTransport;
+    use crate::mtxcli::url::ClientApi;
+    use crate::mtxcli::web::{Device, CLIENT_V3};
+
+    /// Answers questions from a script, remembers what it was told
+    #[derive(Default)]
+    struct ScriptedPrompt {
+        answers: RefCell<VecDeque<&'static str>>,
+        told: RefCell<Vec<String>>,
+        asked: RefCell<Vec<String>>,
+    }
+
+    impl ScriptedPrompt {
+        fn new(answers: &[&'static str]) -> Self {
+            ScriptedPrompt {
+                answers: RefCell::new(answers.iter().copied().collect()),
+                ..ScriptedPrompt::default()
+            }
+        }
+    }
+
+    impl Prompt for ScriptedPrompt {
+        fn tell(&self, text: &str) {
+            self.told.borrow_mut().push(text.to_string());
+        }
+
+        fn ask(&self, question: &str) -> Option<String> {
+            self.asked.borrow_mut().push(question.to_string());
+            self.answers.borrow_mut().pop_front().map(str::to_string)
+        }
+    }
+
+    fn challenge(info: Value) -> Result<Value, MatrixError> {
+        Err(MatrixError::from_status(401, &info.to_string()))
+    }
+
+    fn credentials(password: &str) -> Credentials {
+        Credentials { user: "@alice:localhost".to_string(), password: password.to_string() }
+    }
+
+    fn auth(request: &Request) -> Value {
+        let body: Value = ureq::serde_json::from_str(request.body.as_deref().unwrap()).unwrap();
+        body.get("auth").cloned().unwrap_or(Value::Null)
+    }
+
+    fn register(transport: &ScriptedTransport, prompt: &ScriptedPrompt) -> Result<web::LoginResponse, MatrixError> {
+        let api = ClientApi::new("http://localhost", CLIENT_V3);
+        let device = Device { device_id: None, display_name: "mtxcli".to_string() };
+        authenticate(prompt, &credentials("secret"), |auth| {
+            web::register(transport, &api, "alice", "secret", &device, auth)
+        })
+    }
+
+    fn change_password(transport: &ScriptedTransport, prompt: &ScriptedPrompt,
+                       password: &str) -> Result<(), MatrixError> {
+        let api = ClientApi::new("http://localhost", CLIENT_V3);
+        authenticate(prompt, &credentials(password), |auth| {
+            web::change_password(transport, &api, "token", "new secret", false, auth)
+        })
+    }
+
+    const REGISTERED: &str = r#"{"user_id":"@alice:localhost","access_token":"token1","device_id":"DEVICE1"}"#;
+
+    #[test]
+    fn two_stages_in_one_session() {
+        let transport = ScriptedTransport::new();
+        let flows = json!([
+            { "stages": [ "m.login.recaptcha" ] },
+            { "stages": [ MTX_LOGIN_REGISTRATION_TOKEN, MTX_LOGIN_DUMMY ] },
+        ]);
+        transport
+            .respond(Method::Post, "/register", challenge(json!({ "session": "S1", "flows": flows, "params": {} })))
+            .respond(Method::Post, "/register", challenge(json!({
+                "session": "S1", "flows": flows, "params": {},
+                "completed": [ MTX_LOGIN_REGISTRATION_TOKEN ],
+            })))
+            .respond(Method::Post, "/register", Ok(ureq::serde_json::from_str(REGISTERED).unwrap()));
+        let prompt = ScriptedPrompt::new(&["invite-only"]);
+        let response = register(&transport, &prompt).unwrap();
+        assert_eq!(response.access_token, "token1");
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(auth(&requests[0]), Value::Null);
+        assert_eq!(auth(&requests[1]), json!({
+            "type": MTX_LOGIN_REGISTRATION_TOKEN, "session": "S1", "token": "invite-only",
+        }));
+        // the completed stage is not asked again
+        assert_eq!(auth(&requests[2]), json!({ "type": MTX_LOGIN_DUMMY, "session": "S1" }));
+        assert_eq!(*prompt.asked.borrow(), vec!["registration token:"]);
+    }
+
+    #[test]
+    fn terms() {
+        let transport = ScriptedTransport::new();
+        let terms = json!({
+            "session": "S1",
+            "flows": [ { "stages": [ MTX_LOGIN_TERMS ] } ],
+            "params": { MTX_LOGIN_TERMS: { "policies": { "privacy_policy": {
+                "version": "1.0",
+                "fr": { "name": "Politique de confidentialité", "url": "https://localhost/privacy-fr.html" },
+                "en": { "name": "Privacy Policy", "url": "https://localhost/privacy.html" },
+            } } } },
+        });
+        transport
+            .respond(Method::Post, "/register", challenge(terms.clone()))
+            .respond(Method::Post, "/register", Ok(ureq::serde_json::from_str(REGISTERED).unwrap()))
+            .respond(Method::Post, "/register", challenge(terms));
+        let prompt = ScriptedPrompt::new(&["yes"]);
+        register(&transport, &prompt).unwrap();
+        assert_eq!(*prompt.told.borrow(), vec!["Privacy Policy: https://localhost/privacy.html"]);
+        assert_eq!(auth(&transport.requests()[1]), json!({ "type": MTX_LOGIN_TERMS, "session": "S1" }));
+        // declining stops before anything is sent
+        let prompt = ScriptedPrompt::new(&["n"]);
+        let e = register(&transport, &prompt).unwrap_err();
+        assert_eq!(e, MatrixError::Config("the terms were not accepted".to_string()));
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn wrong_password() {
+        let transport = ScriptedTransport::new();
+        let flows = json!([ { "stages": [ web::MTX_LOGIN_PASSWORD ] } ]);
+        transport
+            .respond(Method::Post, "/account/password", challenge(json!({ "session": "S1", "flows": flows })))
+            .respond(Method::Post, "/account/password", challenge(json!({
+                "session": "S1", "flows": flows,
+                "errcode": "M_FORBIDDEN", "error": "Invalid password",
+            })))
+            .respond(Method::Post, "/account/password", Ok(json!({})));
+        // asked for as the credentials have no password
+        let prompt = ScriptedPrompt::new(&["wrong"]);
+        let e = change_password(&transport, &prompt, "").unwrap_err();
+        assert!(e.interactive_auth().is_some());
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(transport.remaining(), 1);
+        assert_eq!(auth(&transport.requests()[1]), json!({
+            "type": web::MTX_LOGIN_PASSWORD, "session": "S1", "password": "wrong",
+            "identifier": { "type": web::MTX_ID_USER, "user": "@alice:localhost" },
+        }));
+        assert_eq!(*prompt.asked.borrow(), vec!["password:"]);
+    }
+
+    #[test]
+    fn no_supported_flow() {
+        let transport = ScriptedTransport::new();
+        transport.respond(Method::Post, "/account/password", challenge(json!({
+            "session": "S1",
+            "flows": [ { "stages": [ "m.login.recaptcha" ] }, { "stages": [ "m.login.email.identity" ] } ],
+        })));
+        let prompt = ScriptedPrompt::new(&[]);
+        let e = change_password(&transport, &prompt, "secret").unwrap_err();
+        assert_eq!(e, MatrixError::Config(
+            r#"authentication requires unsupported stages: [["m.login.recaptcha"], ["m.login.email.identity"]]"#
+                .to_string()));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn other_errors_are_not_challenges() {
+        let transport = ScriptedTransport::new();
+        transport.respond(Method::Post, "/account/password",
+                          Err(MatrixError::from_status(403, r#"{"errcode":"M_FORBIDDEN","error":"no"}"#)));
+        let e = change_password(&transport, &ScriptedPrompt::new(&[]), "secret").unwrap_err();
+        assert_eq!(e.status(), Some(403));
+        assert_eq!(transport.requests().len(), 1);
+    }
+}
@@ -12,11 +12,62 @@ pub fn encode(v: &str) -> String {
     percent_encoding::utf8_percent_encode(v, RFC3986).to_string()
 }
 
+/// Build a URL from base, path segments (each encoded) and query parameters
+pub fn build(base: &str, segments: &[&str], query: &[(&str, &str)]) -> String {
+    let mut url = String::from(base.trim_end_matches('/'));
+    for segment in segments.iter() {
+        url.push('/');
+        url.push_str(&encode(segment));
+    }
+    let mut separator = '?';
+    for (key, value) in query.iter() {
+        url.push(separator);
+        url.push_str(&encode(key));
+        url.push('=');
+        url.push_str(&encode(value));
+        separator = '&';
+    }
+    url
+}
+
+/// Encode the fields as application/x-www-form-urlencoded
+pub fn form(fields: &[(&str, &str)]) -> String {
+    fields.iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/// URL builder for the client-server API of a homeserver
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientApi {
+    /// e.g. https://matrix.org/_matrix/client/v3
+    base: String,
+}
+
+impl ClientApi {
+    pub fn new(server: &str, prefix: &str) -> Self {
+        let mut base = String::from(server.trim_end_matches('/'));
+        base.push_str(prefix);
+        ClientApi {
+            base,
+        }
+    }
+
+    /// URL for the endpoint
+    pub fn url(&self, segments: &[&str]) -> String {
+        build(&self.base, segments, &[])
+    }
+
+    /// URL for the endpoint with query parameters
+    pub fn url_query(&self, segments: &[&str], query: &[(&str, &str)]) -> String {
+        build(&self.base, segments, query)
+    }
+}
+
 /// URL decode string
-#[allow(dead_code)]
 pub fn decode(v: &str) -> String {
-    let decoded = percent_encoding::percent_decode_str(v)
-        .decode_utf8()
-        .expect("unable to decode");
-    decoded.to_string()
+    percent_encoding::percent_decode_str(v)
+        .decode_utf8_lossy()
+        .to_string()
 }
@@ -1,16 +1,43 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
 use serde::{Serialize,Deserialize};
-use ureq::serde_json::{Value, Map};
+use ureq::serde_json::{json, Value};
 use ureq;
 
+use crate::mtxcli::error::MatrixError;
+use crate::mtxcli::tls;
+use crate::mtxcli::trace;
+use crate::mtxcli::transport::{MatrixTransport, Request};
 use crate::mtxcli::url;
+use crate::mtxcli::url::ClientApi;
 
 const ACCEPT: &str = "Accept";
 const ACCEPT_JSON: &str = "application/json";
+const CONTENT_TYPE: &str = "Content-Type";
+const CONTENT_TYPE_FORM: &str = "application/x-www-form-urlencoded";
 const AUTHORIZATION: &str = "Authorization";
 const BEARER: &str = "Bearer ";
+const HTTPS: &str = "https://";
+
+/// path prefix for the r0 client-server API (before v1.1)
+pub const CLIENT_R0: &str = "/_matrix/client/r0";
+/// path prefix for the v3 client-server API (v1.1 and later)
+pub const CLIENT_V3: &str = "/_matrix/client/v3";
+
+pub const RETRY_MAX_ATTEMPTS: u32 = 4;
+pub const RETRY_MAX_DELAY: u64 = 30000; // ms
+const RETRY_BASE_DELAY: u64 = 500; // ms
+
+pub const CONNECT_TIMEOUT: u64 = 10000; // ms
+pub const READ_TIMEOUT: u64 = 30000; // ms
+pub const SYNC_TIMEOUT: u64 = 300; // ms
+const MAX_IDLE_CONNECTIONS_PER_HOST: usize = 2;
 
 pub const MTX_LOGIN_PASSWORD: &str = "m.login.password";
-const MTX_ID_USER: &str = "m.id.user";
+pub const MTX_LOGIN_SSO: &str = "m.login.sso";
+const MTX_LOGIN_TOKEN: &str = "m.login.token";
+pub const MTX_ID_USER: &str = "m.id.user";
 
 pub fn get_username(user: &str) -> String {
     let i = match user.find('@') {
@@ -24,132 +51,364 @@ pub fn get_username(user: &str) -> String {
     (&user[i..j]).to_string()
 }
 
-fn serialize<T: ?Sized + Serialize>(object: &T) -> Option<String> {
-    match ureq::serde_json::to_string(&object) {
-        Ok(value) => {
-            Some(value)
-        },
-        Err(_) => {
-            // println!("FAILED TO SERIALIZE");
-            None
-        }
+pub fn get_server_name(user: &str) -> String {
+    match user.find(':') {
+        Some(index) => { (&user[index + 1..]).to_string() },
+        None => { String::new() },
     }
 }
 
-pub fn handle_response(maybe_response: Result<ureq::Response,ureq::Error>) -> Option<Value> {
+fn serialize<T: ?Sized + Serialize>(object: &T) -> Result<String, MatrixError> {
+    ureq::serde_json::to_string(&object)
+        .map_err(|e| MatrixError::Serialize(e.to_string()))
+}
+
+/// Reads the status and body of the response
+pub fn read_response(maybe_response: Result<ureq::Response,ureq::Error>) -> Result<(u16, String), MatrixError> {
     match maybe_response {
         Ok(response) => {
-            // DEBUG
-            // if let Ok(json) = response.into_string() {
-            //     println!("JSON = \n{}\n", json);
-            //     None
-            if let Ok(body) = response.into_json() {
-                Some(body)
-            } else {
-                println!("Error: could not convert response into JSON");
-                None
-            }
+            let status = response.status();
+            response.into_string()
+                .map(|body| (status, body))
+                .map_err(|e| MatrixError::Transport(e.to_string()))
         },
         Err(ureq::Error::Status(code, response)) => {
             /* the server returned an unexpected status
             code (such as 400, 500 etc) */
-            let err_body = response.into_string().unwrap();
-            println!("ERROR code {} err_body = {}", code, err_body);
-            // let status_text = "the status text";
-            // Err(ureq::Error::Status(code, ureq::Response::new(code, status_text, &body).unwrap()))
-            None
+            Ok((code, response.into_string().unwrap_or_default()))
+        }
+        Err(ureq::Error::Transport(transport)) => {
+            Err(MatrixError::Transport(transport.to_string()))
+        }
+    }
+}
+
+/// Returns the JSON body (or the error) for the status and body
+pub fn handle_response(status: u16, body: &str) -> Result<Value, MatrixError> {
+    if (200..300).contains(&status) {
+        if body.trim().len() == 0 {
+            // e.g. OAuth 2.0 token revocation answers with an empty body
+            return Ok(Value::Null);
         }
-        Err(_) => {
-            println!("Unknown Error");
-            // Err(e)
+        ureq::serde_json::from_str(body)
+            .map_err(|e| MatrixError::InvalidResponse(format!("could not convert response into JSON: {}", e)))
+    } else {
+        Err(MatrixError::from_status(status, body))
+    }
+}
+
+/// When and how long to wait before retrying a failed request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// maximum number of attempts (including the first one)
+    pub max_attempts: u32,
+    /// longest we are willing to wait before a retry
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, max_delay_ms: u64) -> Self {
+        RetryPolicy {
+            max_attempts,
+            max_delay: Duration::from_millis(max_delay_ms),
+        }
+    }
+
+    /// exponential backoff before retry number `attempt` (starting at 1)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << (attempt - 1).min(16);
+        Duration::from_millis(RETRY_BASE_DELAY.saturating_mul(factor))
+            .min(self.max_delay)
+    }
+
+    /// Returns how long to wait before retrying after `error`,
+    /// or None if we should give up. Requests which are not idempotent
+    /// are only sent again if the server cannot have handled them.
+    fn delay(&self, attempt: u32, error: &MatrixError, failure: Option<ConnectionFailure>,
+             idempotent: bool) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            None
+        } else if error.is_limit_exceeded() {
+            match error.retry_after_ms() {
+                Some(retry_after_ms) => {
+                    let retry_after = Duration::from_millis(retry_after_ms);
+                    if retry_after > self.max_delay {
+                        None
+                    } else {
+                        Some(retry_after)
+                    }
+                },
+                None => Some(self.backoff(attempt)),
+            }
+        } else if failure == Some(ConnectionFailure::Connect)
+            || (idempotent && (failure.is_some() || matches!(error.status(), Some(status) if status >= 500))) {
+            Some(self.backoff(attempt))
+        } else {
             None
         }
     }
 }
 
-pub fn get_json(url: &str) -> Result<ureq::Response, ureq::Error> {
-    // println!("getting json from {}", &url);
-    ureq::get(&url)
-        .set(ACCEPT, ACCEPT_JSON)
-        .call()
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(RETRY_MAX_ATTEMPTS, RETRY_MAX_DELAY)
+    }
 }
 
-pub fn get_json_auth(url: &str, token:&str) -> Result<ureq::Response, ureq::Error> {
-    // println!("getting json auth from {}", &url);
-    let mut authorization = String::from(BEARER);
-    authorization.push_str(token);
-    ureq::get(&url)
-        .set(ACCEPT, ACCEPT_JSON)
-        .set(AUTHORIZATION, &authorization)
-        .call()
+/// A (transient) failure of the connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionFailure {
+    /// resolving or connecting failed: the server never saw the request
+    Connect,
+    /// the connection broke after the request may have been sent
+    Io,
 }
 
-pub fn post_string(url: &str, request_body: &str) -> Result<ureq::Response, ureq::Error> {
-    // println!("post json to {}", &url);
-    ureq::post(&url)
-        .set(ACCEPT, ACCEPT_JSON)
-        .send_string(request_body)
+/// Returns the connection failure, if any (TLS failures are not)
+fn connection_failure(transport: &ureq::Transport) -> Option<ConnectionFailure> {
+    let mut source = std::error::Error::source(transport);
+    while let Some(e) = source {
+        let inner = match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) => io_error.get_ref(),
+            None => None,
+        };
+        if e.is::<rustls::Error>() || matches!(inner, Some(inner) if inner.is::<rustls::Error>()) {
+            return None;
+        }
+        source = e.source();
+    }
+    match transport.kind() {
+        ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed => Some(ConnectionFailure::Connect),
+        ureq::ErrorKind::Io => Some(ConnectionFailure::Io),
+        _ => None,
+    }
+}
+
+/// Configuration of the HTTP layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    /// timeout for establishing a connection
+    pub connect_timeout: Duration,
+    /// timeout for individual socket reads (and writes)
+    pub read_timeout: Duration,
+    /// how long the server may hold a /sync long poll
+    pub sync_timeout: Duration,
+    pub retry: RetryPolicy,
+    /// http://, socks5:// (or socks4://) proxy
+    pub proxy: Option<String>,
+    /// PEM bundle of CA certificates to trust instead of the built in roots
+    pub ca_file: Option<String>,
+    /// base64 SHA-256 of the server's SubjectPublicKeyInfo
+    pub pin: Option<String>,
+    /// log requests and responses (with secrets redacted)
+    pub trace: bool,
 }
 
-pub fn post_string_auth(url: &str, request_body: &str, token: &str) -> Result<ureq::Response, ureq::Error> {
-    // println!("post json auth from {}", &url);
-    let mut authorization = String::from(BEARER);
-    authorization.push_str(token);
-    ureq::post(&url)
-        .set(ACCEPT, ACCEPT_JSON)
-        .set(AUTHORIZATION, &authorization)
-        .send_string(request_body)
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: Duration::from_millis(CONNECT_TIMEOUT),
+            read_timeout: Duration::from_millis(READ_TIMEOUT),
+            sync_timeout: Duration::from_millis(SYNC_TIMEOUT),
+            retry: RetryPolicy::default(),
+            proxy: None,
+            ca_file: None,
+            pin: None,
+            trace: false,
+        }
+    }
 }
 
-pub fn put_string_auth(url: &str, request_body: &str, token: &str) -> Result<ureq::Response, ureq::Error> {
-    // println!("put json auth from {}", &url);
-    let mut authorization = String::from(BEARER);
-    authorization.push_str(token);
-    ureq::put(&url)
-        .set(ACCEPT, ACCEPT_JSON)
-        .set(AUTHORIZATION, &authorization)
-        .send_string(request_body)
+/// The HTTP layer used to talk to the homeserver
+///
+/// All requests share one agent so that connections are kept alive
+/// (and reused) instead of paying for a new TCP+TLS handshake each time.
+#[derive(Debug)]
+pub struct Http {
+    pub config: HttpConfig,
+    agent: ureq::Agent,
 }
 
-// --------------------------------
+impl Http {
+    pub fn new(config: HttpConfig) -> Result<Self, MatrixError> {
+        let agent = Http::build_agent(&config)?;
+        Ok(Http {
+            config,
+            agent,
+        })
+    }
+
+    fn build_agent(config: &HttpConfig) -> Result<ureq::Agent, MatrixError> {
+        let mut builder = ureq::AgentBuilder::new()
+            .timeout_connect(config.connect_timeout)
+            .timeout_read(config.read_timeout)
+            .timeout_write(config.read_timeout)
+            .max_idle_connections_per_host(MAX_IDLE_CONNECTIONS_PER_HOST);
+        if let Some(proxy) = &config.proxy {
+            let proxy = ureq::Proxy::new(proxy)
+                .map_err(|e| MatrixError::Config(format!("invalid proxy {}: {}", proxy, e)))?;
+            builder = builder.proxy(proxy);
+        }
+        if config.ca_file.is_some() || config.pin.is_some() {
+            let tls_config = tls::client_config(config.ca_file.as_deref(), config.pin.as_deref())?;
+            builder = builder.tls_config(tls_config);
+        }
+        Ok(builder.build())
+    }
+
+    /// The overall timeout for a /sync request: the server may hold the
+    /// request for sync_timeout before it even begins to answer
+    pub fn sync_request_timeout(&self) -> Duration {
+        self.config.connect_timeout + self.config.sync_timeout + self.config.read_timeout
+    }
+
+    /// Sends the request once
+    fn send(&self, request: &Request) -> Result<ureq::Response, ureq::Error> {
+        let mut req = self.agent.request(request.method.as_str(), &request.url)
+            .set(ACCEPT, ACCEPT_JSON);
+        if let Some(token) = &request.token {
+            let mut authorization = String::from(BEARER);
+            authorization.push_str(token);
+            req = req.set(AUTHORIZATION, &authorization);
+        }
+        if request.form {
+            req = req.set(CONTENT_TYPE, CONTENT_TYPE_FORM);
+        }
+        if request.long_poll {
+            req = req.timeout(self.sync_request_timeout());
+        }
+        match &request.body {
+            Some(body) => req.send_string(body),
+            None => req.call(),
+        }
+    }
+}
 
-pub fn whoami(server: &str, token: &str) -> bool {
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/r0/account/whoami");
-    if let Some(value) = handle_response(get_json_auth(&url, token)) {
-        if let Value::Object(body) = value {
-            if let Some(Value::String(device_id)) = body.get("device_id") {
-                debug!("device_id = {}", device_id);
+impl MatrixTransport for Http {
+    /// Sends the request (again) according to the RetryPolicy
+    fn request(&self, request: &Request) -> Result<Value, MatrixError> {
+        let mut attempt = 1;
+        loop {
+            if self.config.trace {
+                trace::trace_request(request, attempt);
             }
-            if let Some(Value::String(user_id)) = body.get("user_id") {
-                debug!("user_id = {}", user_id);
+            let start = Instant::now();
+            let response = self.send(request);
+            let failure = match &response {
+                Err(ureq::Error::Transport(transport)) => connection_failure(transport),
+                _ => None,
+            };
+            let response = read_response(response);
+            if self.config.trace {
+                trace::trace_response(request, &response, start.elapsed());
+            }
+            match response.and_then(|(status, body)| handle_response(status, &body)) {
+                Err(e) => {
+                    if let Some(delay) = self.config.retry.delay(attempt, &e, failure, request.idempotent) {
+                        debug!("attempt {} failed: {}, retrying in {:?}", attempt, e, delay);
+                        thread::sleep(delay);
+                        attempt += 1;
+                    } else {
+                        return Err(e);
+                    }
+                },
+                ok => {
+                    return ok;
+                }
             }
         }
-        true
+    }
+
+    fn configure(&mut self, config: &HttpConfig) -> Result<(), MatrixError> {
+        if *config != self.config {
+            self.agent = Http::build_agent(config)?;
+            self.config = config.clone();
+        }
+        Ok(())
+    }
+}
+
+// --------------------------------
+
+/// Returns the spec versions supported by the homeserver
+pub fn get_versions(transport: &dyn MatrixTransport, server: &str) -> Result<Vec<String>, MatrixError> {
+    let url = url::build(server, &["_matrix", "client", "versions"], &[]);
+    let value = transport.get_json(&url)?;
+    if let Some(Value::Array(versions)) = value.get("versions") {
+        Ok(versions.iter()
+           .filter_map(|version| version.as_str())
+           .map(|version| version.to_string())
+           .collect())
     } else {
-        false
-    }
-}
-
-pub fn get_login_type(server: &str) -> bool {
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/r0/login");
-    let mut found = false;
-    if let Some(value) = handle_response(get_json(&url)) {
-        if let Value::Object(body) = value {
-            if let Some(Value::Array(flows)) = body.get("flows") {
-                for flow in flows.iter() {
-                    if let Some(Value::String(login_type)) = flow.get("type") {
-                        if login_type.eq(MTX_LOGIN_PASSWORD) {
-                            found = true;
-                            break;
-                        }
-                    }
+        Err(MatrixError::InvalidResponse("no versions for get_versions".to_string()))
+    }
+}
+
+/// Returns the best client-server API prefix for the supported versions
+pub fn negotiate_prefix(versions: &[String]) -> &'static str {
+    if versions.iter().any(|version| version.starts_with("v1.")) {
+        CLIENT_V3
+    } else {
+        CLIENT_R0
+    }
+}
+
+/// Discovers the homeserver base URL for server_name via .well-known
+pub fn discover_homeserver(transport: &dyn MatrixTransport, server_name: &str) -> Result<String, MatrixError> {
+    let mut base = String::from(HTTPS);
+    base.push_str(server_name);
+    let url = url::build(&base, &[".well-known", "matrix", "client"], &[]);
+    let value = transport.get_json(&url)?;
+    let base_url = match value.get("m.homeserver") {
+        Some(homeserver) => {
+            match homeserver.get("base_url") {
+                Some(Value::String(base_url)) => base_url.trim_end_matches('/').to_string(),
+                _ => {
+                    return Err(MatrixError::InvalidResponse("no m.homeserver.base_url for discover_homeserver".to_string()));
+                }
+            }
+        },
+        None => {
+            return Err(MatrixError::InvalidResponse("no m.homeserver for discover_homeserver".to_string()));
+        }
+    };
+    let versions = get_versions(transport, &base_url)?;
+    debug!("{} supports versions {:?}", base_url, versions);
+    Ok(base_url)
+}
+
+/// Who the access token belongs to
+#[derive(Debug, Clone, Deserialize)]
+pub struct WhoAmI {
+    pub user_id: String,
+    pub device_id: Option<String>,
+}
+
+/// Returns the user_id (and device_id) for the token
+pub fn whoami(transport: &dyn MatrixTransport, api: &ClientApi, token: &str) -> Result<WhoAmI, MatrixError> {
+    let url = api.url(&["account", "whoami"]);
+    let value = transport.get_json_auth(&url, token)?;
+    let whoami: WhoAmI = ureq::serde_json::from_value(value)
+        .map_err(|e| MatrixError::InvalidResponse(format!("invalid response for whoami: {}", e)))?;
+    debug!("user_id = {}, device_id = {:?}", whoami.user_id, whoami.device_id);
+    Ok(whoami)
+}
+
+/// Returns the login types offered by the server
+pub fn get_login_types(transport: &dyn MatrixTransport, api: &ClientApi) -> Result<Vec<String>, MatrixError> {
+    let url = api.url(&["login"]);
+    let mut login_types = Vec::new();
+    let value = transport.get_json(&url)?;
+    if let Value::Object(body) = value {
+        if let Some(Value::Array(flows)) = body.get("flows") {
+            for flow in flows.iter() {
+                if let Some(Value::String(login_type)) = flow.get("type") {
+                    login_types.push(login_type.to_string());
                 }
             }
         }
     }
-    found
+    Ok(login_types)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -165,10 +424,14 @@ struct AuthRequest {
     type_: String,
     identifier: AuthIdentifier,
     password: String,
+    refresh_token: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    initial_device_display_name: String,
 }
 
 impl AuthRequest {
-    pub fn new(user: &str, password: &str) -> Self {
+    pub fn new(user: &str, password: &str, device: &Device) -> Self {
         let identifier = AuthIdentifier {
             type_: MTX_ID_USER.to_string(),
             user: user.to_string()
@@ -176,53 +439,368 @@ impl AuthRequest {
         AuthRequest {
             type_: MTX_LOGIN_PASSWORD.to_string(),
             identifier: identifier,
-            password: password.to_string()
+            password: password.to_string(),
+            refresh_token: true,
+            device_id: device.device_id.clone(),
+            initial_device_display_name: device.display_name.clone()
         }
     }
 }
 
-// fn authenticate_user() -> Result<String, ureq::Error> {
-pub fn authenticate_user(server: &str, user: &str, password: &str) -> Option<String> {
-    let mut maybe_token: Option<String> = None;
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/r0/login");
-    let auth_request = AuthRequest::new(user, password);
-    if let Some(request_body) = serialize(&auth_request) {
-        if let Some(value) = handle_response(post_string(&url, &request_body)) {
-            if let Value::Object(body) = value {
-                if let Some(Value::String(access_token)) = body.get("access_token") {
-                    maybe_token = Some(access_token.to_string())
-                }
-            }
+/// The device to log in as: the same device_id (if we have one) keeps
+/// the device list of the user tidy
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub device_id: Option<String>,
+    pub display_name: String,
+}
+
+/// The response to a successful login
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginResponse {
+    pub user_id: String,
+    pub access_token: String,
+    /// only if the server supports refresh tokens
+    pub refresh_token: Option<String>,
+    pub device_id: Option<String>,
+}
+
+/// The response to a refresh (the refresh token may be unchanged)
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefreshResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+fn parse_login(value: Value, what: &str) -> Result<LoginResponse, MatrixError> {
+    ureq::serde_json::from_value(value)
+        .map_err(|e| MatrixError::InvalidResponse(format!("invalid response for {}: {}", what, e)))
+}
+
+pub fn authenticate_user(transport: &dyn MatrixTransport, api: &ClientApi, user: &str, password: &str,
+                         device: &Device) -> Result<LoginResponse, MatrixError> {
+    let url = api.url(&["login"]);
+    let auth_request = AuthRequest::new(user, password, device);
+    let request_body = serialize(&auth_request)?;
+    let value = transport.post_string(&url, &request_body)?;
+    parse_login(value, "authenticate_user")
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenAuthRequest {
+    #[serde(rename = "type")]
+    type_: String,
+    token: String,
+    refresh_token: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    initial_device_display_name: String,
+}
+
+impl TokenAuthRequest {
+    pub fn new(login_token: &str, device: &Device) -> Self {
+        TokenAuthRequest {
+            type_: MTX_LOGIN_TOKEN.to_string(),
+            token: login_token.to_string(),
+            refresh_token: true,
+            device_id: device.device_id.clone(),
+            initial_device_display_name: device.display_name.clone()
         }
     }
-    maybe_token
-}
-
-pub fn get_room_id(server: &str, room_server: &str, token: &str) -> Option<String> {
-    let room_encoded = url::encode(room_server);
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/v3/directory/room/");
-    url.push_str(&room_encoded);
-    debug!("get_room_id = {}", url);
-    if let Some(value) = handle_response(get_json_auth(&url, token)) {
-        if let Value::Object(body) = value {
-            if let Some(Value::String(room_id)) = body.get("room_id") {
-                Some(room_id.to_string())
-            } else {
-                error!("invalid response for get_room_id");
-                None
-            }
-        } else {
-            error!("invalid response for get_room_id");
-            None
+}
+
+/// The URL to open in the browser to log in with SSO
+pub fn sso_redirect_url(api: &ClientApi, redirect_url: &str) -> String {
+    api.url_query(&["login", "sso", "redirect"], &[("redirectUrl", redirect_url)])
+}
+
+/// Login with the token from SSO
+pub fn authenticate_token(transport: &dyn MatrixTransport, api: &ClientApi, login_token: &str,
+                          device: &Device) -> Result<LoginResponse, MatrixError> {
+    let url = api.url(&["login"]);
+    let auth_request = TokenAuthRequest::new(login_token, device);
+    let request_body = serialize(&auth_request)?;
+    let value = transport.post_string(&url, &request_body)?;
+    parse_login(value, "authenticate_token")
+}
+
+#[derive(Serialize, Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct RegisterRequest<'a> {
+    username: String,
+    password: String,
+    refresh_token: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    initial_device_display_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<&'a Value>,
+}
+
+/// Register the user (and log in as the device): the server will
+/// usually ask for user-interactive authentication (see uia)
+pub fn register(transport: &dyn MatrixTransport, api: &ClientApi, username: &str, password: &str,
+                device: &Device, auth: Option<&Value>) -> Result<LoginResponse, MatrixError> {
+    let url = api.url(&["register"]);
+    let register_request = RegisterRequest {
+        username: username.to_string(),
+        password: password.to_string(),
+        refresh_token: true,
+        device_id: device.device_id.clone(),
+        initial_device_display_name: device.display_name.clone(),
+        auth,
+    };
+    let request_body = serialize(&register_request)?;
+    let value = transport.post_string(&url, &request_body)?;
+    parse_login(value, "register")
+}
+
+/// Exchange the refresh token for a new access token
+pub fn refresh(transport: &dyn MatrixTransport, api: &ClientApi, refresh_token: &str) -> Result<RefreshResponse, MatrixError> {
+    let url = api.url(&["refresh"]);
+    let refresh_request = RefreshRequest { refresh_token: refresh_token.to_string() };
+    let request_body = serialize(&refresh_request)?;
+    let value = transport.post_string(&url, &request_body)?;
+    ureq::serde_json::from_value(value)
+        .map_err(|e| MatrixError::InvalidResponse(format!("invalid response for refresh: {}", e)))
+}
+
+/// Invalidate the access token (or, with all, every token of the user)
+pub fn logout(transport: &dyn MatrixTransport, api: &ClientApi, token: &str, all: bool) -> Result<(), MatrixError> {
+    let url = if all {
+        api.url(&["logout", "all"])
+    } else {
+        api.url(&["logout"])
+    };
+    transport.post_string_auth(&url, "{}", token)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PasswordRequest<'a> {
+    new_password: String,
+    logout_devices: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<&'a Value>,
+}
+
+/// Change the password (with user-interactive authentication), other
+/// devices are logged out if logout_devices
+pub fn change_password(transport: &dyn MatrixTransport, api: &ClientApi, token: &str, new_password: &str,
+                       logout_devices: bool, auth: Option<&Value>) -> Result<(), MatrixError> {
+    let url = api.url(&["account", "password"]);
+    let password_request = PasswordRequest {
+        new_password: new_password.to_string(),
+        logout_devices,
+        auth,
+    };
+    let request_body = serialize(&password_request)?;
+    transport.post_string_auth(&url, &request_body, token)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct DeactivateRequest<'a> {
+    erase: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<&'a Value>,
+}
+
+/// Deactivate the account for good (with user-interactive
+/// authentication), also erasing the messages sent if erase
+pub fn deactivate(transport: &dyn MatrixTransport, api: &ClientApi, token: &str, erase: bool,
+                  auth: Option<&Value>) -> Result<(), MatrixError> {
+    let url = api.url(&["account", "deactivate"]);
+    let deactivate_request = DeactivateRequest { erase, auth };
+    let request_body = serialize(&deactivate_request)?;
+    transport.post_string_auth(&url, &request_body, token)?;
+    Ok(())
+}
+
+/// A device (session) of the user
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub last_seen_ip: Option<String>,
+    /// ms since the epoch
+    pub last_seen_ts: Option<u64>,
+}
+
+/// Returns the timestamp (ms since the epoch) as "YYYY-MM-DD HH:MM UTC"
+pub fn format_timestamp(ts: u64) -> String {
+    let secs = ts / 1000;
+    let (hours, minutes) = ((secs / 3600) % 24, (secs / 60) % 60);
+    // the civil date of the day (Howard Hinnant's algorithm)
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, hours, minutes)
+}
+
+#[derive(Deserialize)]
+struct DevicesResponse {
+    #[serde(default)]
+    devices: Vec<DeviceInfo>,
+}
+
+/// Returns the devices of the user
+pub fn get_devices(transport: &dyn MatrixTransport, api: &ClientApi, token: &str) -> Result<Vec<DeviceInfo>, MatrixError> {
+    let url = api.url(&["devices"]);
+    let value = transport.get_json_auth(&url, token)?;
+    let response: DevicesResponse = ureq::serde_json::from_value(value)
+        .map_err(|e| MatrixError::InvalidResponse(format!("invalid response for devices: {}", e)))?;
+    Ok(response.devices)
+}
+
+#[derive(Serialize)]
+struct RenameDeviceRequest<'a> {
+    display_name: &'a str,
+}
+
+/// Set the display name of the device
+pub fn rename_device(transport: &dyn MatrixTransport, api: &ClientApi, token: &str, device_id: &str,
+                     display_name: &str) -> Result<(), MatrixError> {
+    let url = api.url(&["devices", device_id]);
+    let request_body = serialize(&RenameDeviceRequest { display_name })?;
+    transport.put_string_auth(&url, &request_body, token)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct DeleteDevicesRequest<'a> {
+    devices: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<&'a Value>,
+}
+
+/// Delete the devices, logging them out (with user-interactive
+/// authentication)
+pub fn delete_devices(transport: &dyn MatrixTransport, api: &ClientApi, token: &str, devices: &[String],
+                      auth: Option<&Value>) -> Result<(), MatrixError> {
+    let url = api.url(&["delete_devices"]);
+    let request_body = serialize(&DeleteDevicesRequest { devices, auth })?;
+    transport.post_string_auth(&url, &request_body, token)?;
+    Ok(())
+}
+
+pub fn get_room_id(transport: &dyn MatrixTransport, api: &ClientApi, room_server: &str, token: &str) -> Result<String, MatrixError> {
+    let url = api.url(&["directory", "room", room_server]);
+    let value = transport.get_json_auth(&url, token)?;
+    if let Some(Value::String(room_id)) = value.get("room_id") {
+        Ok(room_id.to_string())
+    } else {
+        Err(MatrixError::InvalidResponse("no room_id for get_room_id".to_string()))
+    }
+}
+
+/// Join the room (by room_id or alias) through the via servers (if
+/// any), returns its room_id
+pub fn join_room(transport: &dyn MatrixTransport, api: &ClientApi, room_id_or_alias: &str,
+                 via: &[String], token: &str) -> Result<String, MatrixError> {
+    // server_name is the name of via before Matrix v1.12
+    let mut query = Vec::new();
+    for server in via.iter() {
+        query.push(("via", server.as_str()));
+        query.push(("server_name", server.as_str()));
+    }
+    let url = api.url_query(&["join", room_id_or_alias], &query);
+    let value = transport.post_string_auth(&url, "{}", token)?;
+    if let Some(Value::String(room_id)) = value.get("room_id") {
+        Ok(room_id.to_string())
+    } else {
+        Err(MatrixError::InvalidResponse("no room_id for join_room".to_string()))
+    }
+}
+
+/// The presets of /createRoom
+pub const ROOM_PRESETS: &[&str] = &["private_chat", "public_chat", "trusted_private_chat"];
+pub const ROOM_VISIBILITIES: &[&str] = &["private", "public"];
+const MEGOLM: &str = "m.megolm.v1.aes-sha2";
+
+/// The options of a new room (the /createRoom request)
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RoomOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// the localpart of the alias
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_alias_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// user_ids to invite
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub invite: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub initial_state: Vec<Value>,
+}
+
+impl RoomOptions {
+    pub fn set_encrypted(&mut self) {
+        if ! self.is_encrypted() {
+            self.initial_state.push(json!({
+                "type": "m.room.encryption",
+                "state_key": "",
+                "content": { "algorithm": MEGOLM },
+            }));
         }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.initial_state.iter().any(|event| event["type"] == "m.room.encryption")
+    }
+}
+
+/// Create a room, returns its room_id (the request is never sent again
+/// once the server may have handled it: that would create another room)
+pub fn create_room(transport: &dyn MatrixTransport, api: &ClientApi, options: &RoomOptions,
+                   token: &str) -> Result<String, MatrixError> {
+    let url = api.url(&["createRoom"]);
+    let request_body = serialize(options)?;
+    let value = transport.post_string_auth(&url, &request_body, token)?;
+    if let Some(Value::String(room_id)) = value.get("room_id") {
+        Ok(room_id.to_string())
     } else {
-        error!("Error for get_room_id");
-        None
+        Err(MatrixError::InvalidResponse("no room_id for create_room".to_string()))
     }
 }
 
+#[derive(Serialize)]
+struct LeaveRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+}
+
+pub fn leave_room(transport: &dyn MatrixTransport, api: &ClientApi, room_id: &str,
+                  reason: Option<&str>, token: &str) -> Result<(), MatrixError> {
+    let url = api.url(&["rooms", room_id, "leave"]);
+    let request_body = serialize(&LeaveRequest { reason })?;
+    transport.post_string_auth(&url, &request_body, token)?;
+    Ok(())
+}
+
+/// Forget a room we have left (it no longer shows up in sync)
+pub fn forget_room(transport: &dyn MatrixTransport, api: &ClientApi, room_id: &str,
+                   token: &str) -> Result<(), MatrixError> {
+    let url = api.url(&["rooms", room_id, "forget"]);
+    transport.post_string_auth(&url, "{}", token)?;
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize)]
 struct EventFilter {
     limit: i32,
@@ -240,23 +818,26 @@ impl EventFilter {
     }
 }
 
+/// The state events we follow (to name the rooms)
+const ROOM_STATE_TYPES: &[&str] = &["m.room.name", "m.room.canonical_alias", "m.room.encryption"];
+/// Members are lazy loaded: only the heroes and senders
+const ROOM_MEMBER: &str = "m.room.member";
+const ROOM_STATE_LIMIT: i32 = 50;
+
 #[derive(Serialize, Deserialize)]
 struct RoomEventFilter {
     limit: i32,
     types: Vec<String>,
-    rooms: Vec<String>,
+    lazy_load_members: bool,
 }
 
 impl RoomEventFilter {
-    pub fn new(limit: i32, room_id: &str, type_0: &str) -> Self {
-        let mut types = Vec::new();
-        types.push(type_0.to_string());
-        let mut rooms: Vec<String> = Vec::new();
-        rooms.push(room_id.to_string());
+    pub fn new(limit: i32, types: &[&str]) -> Self {
+        let types = types.iter().map(|type_| type_.to_string()).collect();
         RoomEventFilter {
             limit,
             types,
-            rooms,
+            lazy_load_members: true,
         }
     }
 }
@@ -265,23 +846,25 @@ impl RoomEventFilter {
 struct RoomFilter {
     account_data: EventFilter,  // Should be RoomEventFilter
     ephemeral: EventFilter,
-    rooms: Vec<String>,
-    state: EventFilter, // Should be StateFilter
+    state: RoomEventFilter, // Should be StateFilter
     timeline: RoomEventFilter,
 }
 
 impl RoomFilter {
-    pub fn new(room_id: &str) -> Self {
+    // all the joined rooms (there is no "rooms" restriction)
+    pub fn new(timeline_limit: i32) -> Self {
         let account_data = EventFilter::new(0);
         let ephemeral = EventFilter::new(0);
-        let mut rooms: Vec<String> = Vec::new();
-        rooms.push(room_id.to_string());
-        let state = EventFilter::new(0);
-        let timeline = RoomEventFilter::new(10, room_id, "m.room.message");
+        let mut types = vec![ROOM_MEMBER];
+        types.extend_from_slice(ROOM_STATE_TYPES);
+        let state = RoomEventFilter::new(ROOM_STATE_LIMIT, &types);
+        // state changes in the timeline keep the names up to date
+        let mut types = vec!["m.room.message"];
+        types.extend_from_slice(ROOM_STATE_TYPES);
+        let timeline = RoomEventFilter::new(timeline_limit, &types);
         RoomFilter {
             account_data,
             ephemeral,
-            rooms,
             state,
             timeline,
         }
@@ -297,14 +880,19 @@ struct FilterRequest {
 }
 
 impl FilterRequest {
-    pub fn new(room_id: &str) -> Self {
+    pub fn new(timeline_limit: i32) -> Self {
         let account_data = EventFilter::new(0);
         let mut event_fields: Vec<String> = Vec::new();
         event_fields.push("type".to_string());
         event_fields.push("sender".to_string());
         event_fields.push("content.body".to_string());
+        event_fields.push("content.name".to_string());
+        event_fields.push("content.alias".to_string());
+        event_fields.push("content.displayname".to_string());
+        event_fields.push("content.membership".to_string());
+        event_fields.push("state_key".to_string());
         let presence = EventFilter::new(0);
-        let room = RoomFilter::new(room_id);
+        let room = RoomFilter::new(timeline_limit);
         FilterRequest {
             account_data,
             event_fields,
@@ -314,108 +902,216 @@ impl FilterRequest {
     }
 }
 
-pub fn get_filter(user: &str, server: &str, room_id: &str, token: &str)
-                  -> Option<String> {
-    let user_encoded = url::encode(user);
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/v3/user/");
-    url.push_str(&user_encoded);
-    url.push_str("/filter");
-    debug!("get_filter = {}", url);
-    let filter_request = FilterRequest::new(room_id);
-    if let Some(request_body) = serialize(&filter_request) {
-        debug!("filter_request = {}", request_body);
-        if let Some(value) = handle_response(post_string_auth(&url, &request_body, token)) {
-            if let Value::Object(body) = value {
-                if let Some(Value::String(filter_id)) = body.get("filter_id") {
-                    debug!("filter_id = {}", filter_id);
-                    Some(filter_id.to_string())
-                } else {
-                    error!("invalid response for get_filter");
-                    None
-                }
-            } else {
-                error!("invalid response for get_filter");
-                None
-            }
-        } else {
-            error!("Error for get_filter");
-            None
-        }
+pub fn get_filter(transport: &dyn MatrixTransport, api: &ClientApi, user: &str,
+                  token: &str) -> Result<String, MatrixError> {
+    let url = api.url(&["user", user, "filter"]);
+    let filter_request = FilterRequest::new(10);
+    let request_body = serialize(&filter_request)?;
+    debug!("filter_request = {}", request_body);
+    let value = transport.post_string_auth(&url, &request_body, token)?;
+    if let Some(Value::String(filter_id)) = value.get("filter_id") {
+        debug!("filter_id = {}", filter_id);
+        Ok(filter_id.to_string())
     } else {
-        error!("Error unable to serialize request for get_filter");
-        None
-    }
-}
-
-fn get_messages(body: Map<String, Value>, room_id: &str) -> String {
-    let mut messages = String::new();
-    if let Some(Value::Object(rooms)) = body.get("rooms") {
-        if let Some(Value::Object(join)) = rooms.get("join") {
-            if let Some(Value::Object(room)) = join.get(room_id) {
-                if let Some(Value::Object(timeline)) = room.get("timeline") {
-                    if let Some(Value::Array(events)) = timeline.get("events") {
-                        for event in events.iter() {
-                            if let Some(Value::String(type_)) = event.get("type") {
-                                if type_.eq("m.room.message") {
-                                    if let Some(Value::String(sender)) = event.get("sender") {
-                                        messages.push_str(&get_username(sender));
-                                    } else {
-                                        messages.push_str("unknown");
-                                    }
-                                    messages.push_str("> ");
-                                    if let Some(Value::Object(content)) = event.get("content") {
-                                        if let Some(Value::String(body)) = content.get("body") {
-                                            messages.push_str(body);
-
-                                        } else {
-                                            messages.push_str("....");
-                                        }
-                                    } else {
-                                        messages.push_str("...");
-                                    }
-                                    messages.push_str("\n");
-                                } // m.room.message
-                            }
-                        } // event
-                    }
+        Err(MatrixError::InvalidResponse("no filter_id for get_filter".to_string()))
+    }
+}
+
+/// A message in the timeline of a room
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub sender: String,
+    pub body: String,
+}
+
+/// What a sync tells us about a joined room. The name and
+/// canonical_alias are only set if they changed (empty if removed),
+/// as are the fields of the room summary and the unread counts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomUpdate {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub canonical_alias: Option<String>,
+    /// set once encryption is enabled (it cannot be disabled)
+    pub encrypted: bool,
+    /// (user_id, display name) of the members we were told about,
+    /// None if they left (or have no display name)
+    pub members: Vec<(String, Option<String>)>,
+    pub heroes: Option<Vec<String>>,
+    pub joined_member_count: Option<u64>,
+    pub invited_member_count: Option<u64>,
+    pub notification_count: Option<u64>,
+    pub highlight_count: Option<u64>,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    pub joined: Vec<RoomUpdate>,
+    /// the room_ids of the rooms we left (or were kicked from)
+    pub left: Vec<String>,
+}
+
+fn get_events<'a>(room: &'a Value, section: &str) -> impl Iterator<Item = &'a Value> {
+    room.pointer(&format!("/{}/events", section))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+}
+
+fn get_room_update(room_id: &str, room: &Value) -> RoomUpdate {
+    let mut update = RoomUpdate {
+        room_id: room_id.to_string(),
+        ..Default::default()
+    };
+    for event in get_events(room, "state").chain(get_events(room, "timeline")) {
+        let content = |field: &str| {
+            event.get("content")
+                .and_then(|content| content.get(field))
+                .and_then(Value::as_str)
+                .map(|value| value.to_string())
+        };
+        match event.get("type").and_then(Value::as_str) {
+            Some("m.room.message") => {
+                let sender = event.get("sender")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown")
+                    .to_string();
+                let body = content("body").unwrap_or_else(|| "....".to_string());
+                update.messages.push(Message { sender, body });
+            },
+            Some("m.room.name") => {
+                update.name = Some(content("name").unwrap_or_default());
+            },
+            Some("m.room.canonical_alias") => {
+                update.canonical_alias = Some(content("alias").unwrap_or_default());
+            },
+            Some("m.room.encryption") => {
+                update.encrypted = true;
+            },
+            Some(ROOM_MEMBER) => {
+                if let Some(user_id) = event.get("state_key").and_then(Value::as_str) {
+                    let display_name = match content("membership").as_deref() {
+                        Some("join") | Some("invite") => content("displayname"),
+                        _ => None,
+                    };
+                    update.members.push((user_id.to_string(), display_name));
                 }
-            }
+            },
+            _ => { }
         }
     }
-    messages
+    let count = |pointer: &str| room.pointer(pointer).and_then(Value::as_u64);
+    update.heroes = room.pointer("/summary/m.heroes")
+        .and_then(Value::as_array)
+        .map(|heroes| heroes.iter().filter_map(Value::as_str).map(|hero| hero.to_string()).collect());
+    update.joined_member_count = count("/summary/m.joined_member_count");
+    update.invited_member_count = count("/summary/m.invited_member_count");
+    update.notification_count = count("/unread_notifications/notification_count");
+    update.highlight_count = count("/unread_notifications/highlight_count");
+    update
 }
 
-pub fn client_sync(server: &str, filter: &str, since: &str, timeout: i32,
-                   room_id: &str, token: &str) -> Option<(String, String)> {
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/r0/sync?filter=");
-    url.push_str(filter);
-    url.push_str("&timeout=");
-    url.push_str(&timeout.to_string());
-    if since.len() > 0 {
-        url.push_str("&since=");
-        url.push_str(since);
-    }
-    debug!("client_sync = {}", url);
-    if let Some(value) = handle_response(get_json_auth(&url, token)) {
-        // println!("SYNC = {:?}", value);
-        if let Value::Object(body) = value {
-            if let Some(Value::String(next_batch)) = body.get("next_batch") {
-                Some((next_batch.to_string(), get_messages(body, room_id)))
-            } else {
-                error!("invalid response for client_sync");
-                None
-            }
-        } else {
-            error!("Error for client_sync: deserialization");
-            None
+fn get_sync_response(value: Value) -> Result<SyncResponse, MatrixError> {
+    let next_batch = match value.get("next_batch") {
+        Some(Value::String(next_batch)) => next_batch.to_string(),
+        _ => {
+            return Err(MatrixError::InvalidResponse("no next_batch for client_sync".to_string()));
         }
+    };
+    let rooms = |membership: &str| {
+        value.pointer(&format!("/rooms/{}", membership))
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+    };
+    let joined = rooms("join")
+        .map(|(room_id, room)| get_room_update(room_id, room))
+        .collect();
+    let left = rooms("leave")
+        .map(|(room_id, _)| room_id.to_string())
+        .collect();
+    Ok(SyncResponse { next_batch, joined, left })
+}
+
+pub fn client_sync(transport: &dyn MatrixTransport, api: &ClientApi, filter: &str,
+                   since: &str, timeout: Duration, token: &str)
+                   -> Result<SyncResponse, MatrixError> {
+    let timeout = timeout.as_millis().to_string();
+    let mut query = vec![("filter", filter), ("timeout", timeout.as_str())];
+    if since.len() > 0 {
+        query.push(("since", since));
+    }
+    let url = api.url_query(&["sync"], &query);
+    let value = transport.get_json_auth_poll(&url, token)?;
+    if value.is_object() {
+        get_sync_response(value)
+    } else {
+        Err(MatrixError::InvalidResponse("client_sync body is not an object".to_string()))
+    }
+}
+
+/// Returns the room_ids of the joined rooms
+pub fn get_joined_room_ids(transport: &dyn MatrixTransport, api: &ClientApi,
+                           token: &str) -> Result<Vec<String>, MatrixError> {
+    let url = api.url(&["joined_rooms"]);
+    let value = transport.get_json_auth(&url, token)?;
+    if let Some(Value::Array(room_ids)) = value.get("joined_rooms") {
+        Ok(room_ids.iter()
+           .filter_map(Value::as_str)
+           .map(|room_id| room_id.to_string())
+           .collect())
     } else {
-        None
+        Err(MatrixError::InvalidResponse("no joined_rooms for get_joined_room_ids".to_string()))
     }
 }
 
+// the content of the state event (None if the room has none)
+fn get_state_content(transport: &dyn MatrixTransport, api: &ClientApi, room_id: &str,
+                     event_type: &str, token: &str) -> Result<Option<Value>, MatrixError> {
+    let url = api.url(&["rooms", room_id, "state", event_type, ""]);
+    match transport.get_json_auth(&url, token) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.status() == Some(404) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns what a room is shown by (when sync has not told us): its
+/// name, canonical alias and encryption, plus the members of a room
+/// with neither name nor alias (to name it after them)
+pub fn get_room_state(transport: &dyn MatrixTransport, api: &ClientApi, room_id: &str,
+                      user: &str, token: &str) -> Result<RoomUpdate, MatrixError> {
+    let field = |content: Option<Value>, field: &str| {
+        content.map(|content| content.get(field).and_then(Value::as_str).unwrap_or_default().to_string())
+    };
+    let mut update = RoomUpdate {
+        room_id: room_id.to_string(),
+        ..Default::default()
+    };
+    update.name = field(get_state_content(transport, api, room_id, "m.room.name", token)?, "name");
+    update.canonical_alias = field(get_state_content(transport, api, room_id, "m.room.canonical_alias", token)?, "alias");
+    update.encrypted = get_state_content(transport, api, room_id, "m.room.encryption", token)?.is_some();
+    let named = |value: &Option<String>| matches!(value, Some(value) if value.len() > 0);
+    if ! named(&update.name) && ! named(&update.canonical_alias) {
+        let url = api.url(&["rooms", room_id, "joined_members"]);
+        let value = transport.get_json_auth(&url, token)?;
+        let joined = value.get("joined").and_then(Value::as_object).into_iter().flatten();
+        for (user_id, member) in joined {
+            let display_name = member.get("display_name").and_then(Value::as_str);
+            update.members.push((user_id.to_string(), display_name.map(|name| name.to_string())));
+        }
+        update.heroes = Some(update.members.iter()
+                             .map(|(user_id, _)| user_id.to_string())
+                             .filter(|user_id| user_id != user)
+                             .collect());
+        update.joined_member_count = Some(update.members.len() as u64);
+        update.invited_member_count = Some(0);
+    }
+    Ok(update)
+}
+
 pub fn gen_txn_id() -> String {
     let mut bytes = [0u8; 4];
     getrandom::getrandom(&mut bytes).expect("couldn't get random data");
@@ -440,31 +1136,180 @@ impl MessageRequest {
     }
 }
 
-pub fn send_message(server: &str, room_id: &str, text: &str, token: &str) -> bool {
-    let room_id_encoded = url::encode(room_id);
-    let mut url = String::from(server);
-    url.push_str("/_matrix/client/r0/rooms/");
-    url.push_str(&room_id_encoded);
-    url.push_str("/send/m.room.message/");
-    url.push_str(&gen_txn_id());
-    debug!("send_message = {}", url);
+pub fn send_message(transport: &dyn MatrixTransport, api: &ClientApi, room_id: &str, text: &str, token: &str) -> Result<(), MatrixError> {
+    let txn_id = gen_txn_id();
+    let url = api.url(&["rooms", room_id, "send", "m.room.message", &txn_id]);
     let message_request = MessageRequest::new(text);
-    if let Some(request_body) = serialize(&message_request) {
-        // println!("request_body = {}", request_body);
-        if let Some(value) = handle_response(put_string_auth(&url, &request_body, token)) {
-            if let Value::Object(_body) = value {
-                // println!("SENT = {:?}", body);
-                true
-            } else {
-                error!("invalid response for send_message");
-                false
-            }
-        } else {
-            error!("Error for send_message");
-            false
-        }
+    let request_body = serialize(&message_request)?;
+    let value = transport.put_string_auth(&url, &request_body, token)?;
+    if let Value::Object(_body) = value {
+        Ok(())
     } else {
-        error!("Error unable to serialize request for send_message");
-        false
+        Err(MatrixError::InvalidResponse("send_message body is not an object".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn error(status: u16, body: &str) -> MatrixError {
+        MatrixError::from_status(status, body)
+    }
+
+    #[test]
+    fn retry_idempotent_requests() {
+        let policy = RetryPolicy::new(4, 30000);
+        let unavailable = error(503, "{}");
+        let reset = MatrixError::Transport("connection reset".to_string());
+        assert!(policy.delay(1, &unavailable, None, true).is_some());
+        assert!(policy.delay(1, &reset, Some(ConnectionFailure::Io), true).is_some());
+        assert!(policy.delay(4, &unavailable, None, true).is_none());
+        assert!(policy.delay(1, &error(404, r#"{"errcode":"M_NOT_FOUND"}"#), None, true).is_none());
+    }
+
+    #[test]
+    fn retry_other_requests_only_when_unseen() {
+        let policy = RetryPolicy::new(4, 30000);
+        let unavailable = error(503, "{}");
+        let reset = MatrixError::Transport("connection reset".to_string());
+        let refused = MatrixError::Transport("connection refused".to_string());
+        let limited = error(429, r#"{"errcode":"M_LIMIT_EXCEEDED","retry_after_ms":100}"#);
+        assert!(policy.delay(1, &unavailable, None, false).is_none());
+        assert!(policy.delay(1, &reset, Some(ConnectionFailure::Io), false).is_none());
+        assert!(policy.delay(1, &refused, Some(ConnectionFailure::Connect), false).is_some());
+        assert_eq!(policy.delay(1, &limited, None, false), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn create_room_request() {
+        use crate::mtxcli::transport::Method;
+        use crate::mtxcli::transport::scripted::ScriptedTransport;
+        let transport = ScriptedTransport::new();
+        transport.respond(Method::Post, "/createRoom", Ok(json!({ "room_id": "!new:localhost" })));
+        let api = ClientApi::new("http://localhost", CLIENT_V3);
+        let mut options = RoomOptions {
+            name: Some("Incident 42".to_string()),
+            room_alias_name: Some("inc-42".to_string()),
+            ..RoomOptions::default()
+        };
+        options.set_encrypted();
+        let room_id = create_room(&transport, &api, &options, "token").unwrap();
+        assert_eq!(room_id, "!new:localhost");
+        let requests = transport.requests();
+        assert!(! requests[0].idempotent);
+        let body: Value = ureq::serde_json::from_str(requests[0].body.as_deref().unwrap()).unwrap();
+        assert_eq!(body["room_alias_name"], "inc-42");
+        assert_eq!(body["initial_state"][0]["type"], "m.room.encryption");
+        assert!(body.get("topic").is_none());
+    }
+
+    fn http_response(status: &str, body: &str) -> Option<String> {
+        Some(format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                      Connection: close\r\n\r\n{}", status, body.len(), body))
+    }
+
+    // answers each connection to the listener with the next response
+    // (None: close the connection once the request is read), counting
+    // the requests read
+    fn serve(listener: TcpListener, responses: Vec<Option<String>>) -> Arc<AtomicUsize> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for (stream, response) in listener.incoming().zip(responses) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                if let Some(response) = response {
+                    reader.get_mut().write_all(response.as_bytes()).unwrap();
+                }
+            }
+        });
+        requests
+    }
+
+    // the real HTTP transport, retrying quickly
+    fn http(max_attempts: u32) -> Http {
+        Http::new(HttpConfig {
+            retry: RetryPolicy::new(max_attempts, 20),
+            ..HttpConfig::default()
+        }).unwrap()
+    }
+
+    fn api(address: SocketAddr) -> ClientApi {
+        ClientApi::new(&format!("http://{}", address), CLIENT_V3)
+    }
+
+    const CREATED: &str = r#"{"room_id":"!new:localhost"}"#;
+
+    #[test]
+    fn create_room_not_resent_after_connection_breaks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = api(listener.local_addr().unwrap());
+        let requests = serve(listener, vec![None, http_response("200 OK", CREATED)]);
+        // the server may have created the room: it must not be asked again
+        assert!(create_room(&http(4), &api, &RoomOptions::default(), "token").is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn create_room_not_resent_on_server_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = api(listener.local_addr().unwrap());
+        let requests = serve(listener, vec![http_response("503 Service Unavailable", "{}"),
+                                            http_response("200 OK", CREATED)]);
+        let e = create_room(&http(4), &api, &RoomOptions::default(), "token").unwrap_err();
+        assert_eq!(e.status(), Some(503));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn get_resent_on_server_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = api(listener.local_addr().unwrap());
+        let requests = serve(listener, vec![None, http_response("503 Service Unavailable", "{}"),
+                                            http_response("200 OK", r#"{"joined_rooms":["!a:localhost"]}"#)]);
+        let room_ids = get_joined_room_ids(&http(4), &api, "token").unwrap();
+        assert_eq!(room_ids, vec!["!a:localhost".to_string()]);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn create_room_resent_when_connection_fails() {
+        // nothing listens on the port (yet): the server never saw the request
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let api = api(address);
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            serve(TcpListener::bind(address).unwrap(), vec![http_response("200 OK", CREATED)])
+        });
+        let room_id = create_room(&http(20), &api, &RoomOptions::default(), "token").unwrap();
+        assert_eq!(room_id, "!new:localhost");
+        assert_eq!(server.join().unwrap().load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn post_is_not_idempotent() {
+        use crate::mtxcli::transport::Method;
+        assert!(Request::new(Method::Get, "/sync").idempotent);
+        assert!(Request::new(Method::Put, "/send/m.room.message/1").idempotent);
+        assert!(! Request::new(Method::Post, "/login").idempotent);
     }
 }
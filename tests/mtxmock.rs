@@ -0,0 +1,390 @@
+//! End to end tests: mtxcli talking to mtxmock
+//!
+//! Each test starts mtxmock on a free port and runs mtxcli against it
+//! with a fresh HOME (so the configuration and secret store are empty).
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for mtxcli to print what we expect
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+// collects what is read (e.g. from a child process) in the background
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<String>>);
+
+impl Output {
+    fn collect(mut reader: impl Read + Send + 'static) -> Self {
+        let output = Output::default();
+        let text = output.0.clone();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            while let Ok(n) = reader.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                text.lock().unwrap().push_str(&String::from_utf8_lossy(&buffer[..n]));
+            }
+        });
+        output
+    }
+
+    fn text(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// mtxmock with the default users and rooms (killed on drop)
+struct Mock {
+    child: Child,
+    url: String,
+    /// the requests handled (and their status)
+    log: Output,
+}
+
+impl Mock {
+    fn start(args: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mtxmock"))
+            .args(["--port", "0", "--verbose"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("cannot start mtxmock");
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let url = line.trim().strip_prefix("listening on ")
+            .unwrap_or_else(|| panic!("unexpected mtxmock output: {}", line))
+            .to_string();
+        let log = Output::collect(child.stderr.take().unwrap());
+        Mock { child, url, log }
+    }
+
+    // the number of requests logged since `from` (e.g. "POST /_matrix/client/v3/refresh => 200")
+    fn count(&self, from: usize, request: &str) -> usize {
+        self.log.text()[from..].lines().filter(|line| *line == request).count()
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+// an empty HOME for mtxcli (removed on drop)
+struct Home {
+    path: PathBuf,
+}
+
+impl Home {
+    fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("mtxcli-e2e-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        Home { path }
+    }
+}
+
+impl Drop for Home {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+// mtxcli reading its commands from us
+struct Mtxcli {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    output: Output,
+    /// how much of the output was expected already
+    seen: usize,
+}
+
+impl Mtxcli {
+    fn start(home: &Home) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mtxcli"))
+            .env("HOME", &home.path)
+            .env("XDG_CONFIG_HOME", home.path.join(".config"))
+            .env_remove("RUST_LOG")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("cannot start mtxcli");
+        let stdin = child.stdin.take();
+        let output = Output::collect(child.stdout.take().unwrap());
+        Mtxcli { child, stdin, output, seen: 0 }
+    }
+
+    fn say(&mut self, line: &str) {
+        writeln!(self.stdin.as_ref().unwrap(), "{}", line).unwrap();
+    }
+
+    // wait for mtxcli to print text (after what it printed before)
+    fn expect(&mut self, text: &str) {
+        let start = Instant::now();
+        loop {
+            let output = self.output.text();
+            if let Some(i) = output[self.seen..].find(text) {
+                self.seen += i + text.len();
+                return;
+            }
+            if start.elapsed() > EXPECT_TIMEOUT {
+                panic!("expected {:?} after {:?}", text, &output[..self.seen]);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // wait for mtxcli to print text, returns the rest of that line
+    fn expect_line(&mut self, text: &str) -> String {
+        self.expect(text);
+        let start = self.seen;
+        self.expect("\n");
+        self.output.text()[start..self.seen].trim().to_string()
+    }
+
+    // point mtxcli at mtxmock as user, without retrying the discovery
+    // of the homeserver for localhost
+    fn connect(&mut self, server: &str, user: &str) {
+        self.say("/set retry_max_attempts 1");
+        self.say(&format!("/set user {}", user));
+        self.say(&format!("/set server {}", server));
+    }
+
+    // answer the prompt for a new passphrase (declining with None)
+    fn choose_passphrase(&mut self, passphrase: Option<&str>) {
+        self.expect("new passphrase:");
+        match passphrase {
+            Some(passphrase) => {
+                self.say(passphrase);
+                self.say(passphrase);
+            },
+            None => self.say(""),
+        }
+    }
+
+    // log in as user with the password of the scripted users
+    fn login(&mut self, server: &str, user: &str, passphrase: Option<&str>) {
+        self.connect(server, user);
+        self.say("/password");
+        self.say("secret");
+        self.say("/login");
+        self.choose_passphrase(passphrase);
+        self.expect("logged in");
+    }
+
+    // close stdin, returns everything mtxcli printed
+    fn finish(mut self) -> String {
+        drop(self.stdin.take());
+        let status = self.child.wait().unwrap();
+        // let the output be collected
+        thread::sleep(Duration::from_millis(50));
+        let output = self.output.text();
+        assert!(status.success(), "mtxcli failed: {}", output);
+        output
+    }
+}
+
+// what a browser does with a URL mtxcli asks us to open
+fn open(url: &str) {
+    let response = ureq::get(url).call().unwrap_or_else(|e| panic!("cannot open {}: {}", url, e));
+    assert_eq!(response.status(), 200, "{}", url);
+}
+
+#[test]
+fn login_send_and_sync() {
+    let mock = Mock::start(&[]);
+    let home = Home::new("send");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.login(&mock.url, "@alice:localhost", None);
+    mtxcli.say("/set room #test:localhost");
+    mtxcli.say("hello bob");
+    // the scripted history and then our own message, back from /sync
+    mtxcli.expect("#test bob> Welcome to the mock homeserver!");
+    mtxcli.expect("#test alice> hello bob");
+    mtxcli.finish();
+}
+
+#[test]
+fn wrong_password() {
+    let mock = Mock::start(&[]);
+    let home = Home::new("password");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.connect(&mock.url, "@alice:localhost");
+    mtxcli.say("/password");
+    mtxcli.say("wrong");
+    mtxcli.say("/login");
+    mtxcli.expect("authentication failed");
+    mtxcli.say("hello bob");
+    let output = mtxcli.finish();
+    assert!(! output.contains("logged in"), "{}", output);
+    assert!(! output.contains("alice> hello bob"), "{}", output);
+}
+
+#[test]
+fn refresh_expired_token() {
+    let mock = Mock::start(&["--token-lifetime-ms", "500"]);
+    let home = Home::new("refresh");
+    let mut mtxcli = Mtxcli::start(&home);
+    // keep the tokens in the secret store
+    mtxcli.login(&mock.url, "@alice:localhost", Some("passphrase"));
+    mtxcli.say("/set room #test:localhost");
+    mtxcli.say("hello");
+    mtxcli.expect("#test alice> hello");
+    thread::sleep(Duration::from_millis(700));
+    let expired = mock.log.text().len();
+    mtxcli.say("hello after refresh");
+    mtxcli.expect("#test alice> hello after refresh");
+    mtxcli.finish();
+    // the expired token is refused once, then refreshed
+    let log = mock.log.text()[expired..].to_string();
+    assert!(log.lines().next().unwrap().ends_with("=> 401"), "{}", log);
+    assert_eq!(mock.count(expired, "POST /_matrix/client/v3/refresh => 200"), 1, "{}", log);
+    assert_eq!(log.matches("=> 401").count(), 1, "{}", log);
+
+    // refresh tokens are single use: the rotated one must have been kept
+    thread::sleep(Duration::from_millis(700));
+    let restarted = mock.log.text().len();
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.expect("passphrase:");
+    mtxcli.say("passphrase");
+    mtxcli.say("hello again");
+    mtxcli.expect("#test alice> hello again");
+    mtxcli.finish();
+    assert_eq!(mock.count(restarted, "POST /_matrix/client/v3/refresh => 200"), 1);
+    assert_eq!(mock.count(restarted, "POST /_matrix/client/v3/refresh => 401"), 0);
+    assert_eq!(mock.count(restarted, "POST /_matrix/client/v3/login => 200"), 0);
+}
+
+#[test]
+fn register_with_token_and_terms() {
+    let mock = Mock::start(&[]);
+    let home = Home::new("register");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.connect(&mock.url, "@carol:localhost");
+    mtxcli.say("/password");
+    mtxcli.say("secret");
+    mtxcli.say("/register");
+    mtxcli.expect("registration token:");
+    mtxcli.say("letmein");
+    mtxcli.expect("Privacy Policy: https://example.org/privacy-1.0.html");
+    mtxcli.expect("do you accept the terms? [y/N]");
+    mtxcli.say("y");
+    mtxcli.choose_passphrase(None);
+    mtxcli.expect("registered and logged in as @carol:localhost");
+    mtxcli.say("/rooms");
+    mtxcli.expect("no rooms");
+    mtxcli.finish();
+    // one registration, completing the token and terms stages
+    assert_eq!(mock.count(0, "POST /_matrix/client/v3/register => 200"), 1);
+}
+
+#[test]
+fn create_leave_and_join_rooms() {
+    let mock = Mock::start(&[]);
+    let home = Home::new("rooms");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.login(&mock.url, "@alice:localhost", None);
+    mtxcli.say("/create Planning alias=planning topic=\"weekly planning\"");
+    mtxcli.expect("created #planning:localhost");
+    // the room created is the current one
+    mtxcli.say("hi planners");
+    mtxcli.expect("#planning alice> hi planners");
+    mtxcli.say("/rooms");
+    mtxcli.expect("Random (#random:localhost)");
+    mtxcli.expect("* Planning (#planning:localhost)");
+    mtxcli.say("/leave #random:localhost bye");
+    mtxcli.expect("left #random");
+    mtxcli.say("/rooms");
+    mtxcli.expect("* Planning (#planning:localhost)");
+    mtxcli.say("/join #random:localhost");
+    mtxcli.expect("joined #random:localhost");
+    let output = mtxcli.finish();
+    let rooms = output.rsplit("left #random").next().unwrap();
+    let rooms = rooms.split("joined #random").next().unwrap();
+    assert!(! rooms.contains("Random"), "{}", output);
+}
+
+#[test]
+fn rename_and_delete_devices() {
+    let mock = Mock::start(&[]);
+    // log in from another device first
+    let other_home = Home::new("other-device");
+    let mut other = Mtxcli::start(&other_home);
+    other.login(&mock.url, "@alice:localhost", None);
+    other.finish();
+
+    let home = Home::new("devices");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.login(&mock.url, "@alice:localhost", None);
+    mtxcli.say("/devices");
+    // the current device was seen last, so it is listed first
+    let current = mtxcli.expect_line("* ");
+    let other = mtxcli.expect_line("  ");
+    let current = current.split_whitespace().next().unwrap().to_string();
+    let other = other.split_whitespace().next().unwrap().to_string();
+    assert_ne!(current, other);
+    mtxcli.say(&format!("/device rename {} Phone", other));
+    mtxcli.expect(&format!("renamed {} to Phone", other));
+    // deleting a device asks for the password again
+    mtxcli.say(&format!("/device delete {}", other));
+    mtxcli.expect("password:");
+    mtxcli.say("secret");
+    mtxcli.expect(&format!("deleted {}", other));
+    mtxcli.say("/devices");
+    mtxcli.expect(&format!("* {}", current));
+    let output = mtxcli.finish();
+    let devices = output.rsplit(&format!("deleted {}", other)).next().unwrap();
+    assert!(! devices.contains(&other), "{}", output);
+}
+
+#[test]
+fn login_with_sso() {
+    let mock = Mock::start(&[]);
+    let home = Home::new("sso");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.connect(&mock.url, "@alice:localhost");
+    mtxcli.say("/login sso");
+    // mtxmock logs the SSO user in and redirects back to mtxcli
+    let url = mtxcli.expect_line("please open this URL in your browser to log in:\n");
+    open(&url);
+    mtxcli.choose_passphrase(None);
+    mtxcli.expect("logged in as @alice:localhost");
+    mtxcli.say("/set room #test:localhost");
+    mtxcli.say("hello from sso");
+    mtxcli.expect("#test alice> hello from sso");
+    mtxcli.finish();
+    assert_eq!(mock.count(0, "POST /_matrix/client/v3/login => 200"), 1);
+}
+
+#[test]
+fn login_with_oidc() {
+    let mock = Mock::start(&[]);
+    let home = Home::new("oidc");
+    let mut mtxcli = Mtxcli::start(&home);
+    mtxcli.connect(&mock.url, "@alice:localhost");
+    mtxcli.say("/login oidc");
+    mtxcli.expect("enter the code: ");
+    // approve the device grant mtxcli is polling for
+    let url = mtxcli.expect_line("or open ");
+    open(&url);
+    mtxcli.choose_passphrase(None);
+    mtxcli.expect("logged in as @alice:localhost");
+    mtxcli.say("/logout all");
+    mtxcli.expect("cannot log out all devices of an OIDC login");
+    mtxcli.say("/logout");
+    mtxcli.expect("logged out");
+    mtxcli.finish();
+    // the session is revoked at the issuer rather than with /logout
+    assert_eq!(mock.count(0, "POST /oauth2/revoke => 200"), 1, "{}", mock.log.text());
+    assert_eq!(mock.count(0, "POST /_matrix/client/v3/logout/all => 200"), 0);
+}